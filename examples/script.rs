@@ -1,3 +1,3 @@
-fn main () {
+fn main() {
     println!("Hello World!");
-}
\ No newline at end of file
+}
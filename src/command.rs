@@ -1,10 +1,25 @@
+use crate::interpolate::{interpolate, interpolate_for_shell};
+use crate::process;
 use crate::utils::convert_json_to_toml;
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use toml::Value;
 //=============| STRUCT |==============//
 
+/// Collects what a command passes on to the commands after it
+pub type ExportSink = std::sync::Arc<std::sync::Mutex<Exported>>;
+
+#[derive(Debug, Default)]
+pub struct Exported {
+    // Environment variables written to `$COMMANDER_ENV` by commands with `export_env`
+    pub env: Vec<(String, String)>,
+    // Values written to `$COMMANDER_OUTPUT`, as `steps.<name>.outputs.<key>`
+    pub outputs: Vec<(String, String)>,
+}
+
 #[derive(Debug)]
 pub struct Command {
     // Whether this is a section or a singular command
@@ -17,26 +32,74 @@ pub struct Command {
     pub until: Option<i32>,
     // Environment variables to add to the command
     pub env: HashMap<String, String>,
+    // Environment variables to remove from the environment the command runs with
+    pub env_remove: Vec<String>,
+    // Whether the command only gets the environment variables it sets itself
+    pub env_clear: bool,
+    // Environment variables whose values are replaced with `***` in the output
+    pub secret_env: Vec<String>,
     // The arguments to the command itself, not to std
     pub args: HashMap<String, String>,
-    // Whether to load .env file
-    pub load_dotenv: bool,
+    // Arguments whose values are substituted as is instead of being escaped for the shell
+    pub raw_args: Vec<String>,
+    // Dotenv files to load when the command runs, relative to the file it's defined in
+    pub env_files: Vec<String>,
+    // Variables loaded from dotenv files, which are escaped when substituted into the command
+    pub quoted_env: Vec<String>,
     // Which directory to run the command in
     pub working_dir: String,
-    // The command to run
+    // Directory of the file the command was defined in, the working directory is relative to it
+    pub project_root: String,
+    // The command to run, split into words the way a shell would
     pub command: Vec<String>,
+    // The command as it was written, which is what the shell runs
+    pub command_line: String,
+    // Whether to spawn the program directly instead of running the command in a shell
+    pub exec: bool,
     // Whether or not children should run in parallel
     pub parallel: bool,
+    // How many children may run at the same time when running in parallel
+    pub jobs: Option<usize>,
     // Command to run before all other commands, e.g. to set up environment variables
     pub prefix: Vec<Command>,
     // Command to run after all other commands, e.g. for cleanup
     pub suffix: Vec<Command>,
     // How long to sleep before running
     pub delay: f64,
+    // When and how to run the command again if it fails
+    pub retry: Option<Retry>,
+    // Whether the environment variables written to `$COMMANDER_ENV` are passed on to the
+    // commands after it
+    pub export_env: bool,
+    // Where the exported environment variables and outputs end up, set by whatever runs the
+    // command
+    pub export_sink: Option<ExportSink>,
+    // Name the commands after it use to refer to its outputs
+    pub name: String,
+    // Outputs of the commands before it, as `steps.<name>.outputs.<key>`
+    pub step_outputs: HashMap<String, String>,
+    // Whether to keep the command running in the background while the commands after it run
+    pub service: bool,
+    // When a service is ready for the commands after it, right away if not set
+    pub ready_when: Option<ReadyWhen>,
+    // How many seconds each run of the command may take
+    pub timeout: Option<f64>,
+    // How many seconds the whole command may take, including repeats and children
+    pub total_timeout: Option<f64>,
+    // How many seconds a stopped process gets to exit before it's killed
+    pub kill_grace: f64,
+    // When the command has to be done, inherited from the commands it runs under
+    pub deadline: Option<Instant>,
+    // Whether to stop the other children as soon as one of them fails
+    pub fail_fast: bool,
+    // Stops the command when Ctrl-C is pressed or a sibling fails, inherited like the deadline
+    pub cancellation: process::Cancellation,
     // Which shell to run the command in
     pub shell: String,
-    // File handles that need to stay open for the duration the command exists
-    pub file_handles: Vec<NamedTempFile>,
+    // Error to report instead of running the command, e.g. when it has no variant for the platform
+    pub error: Option<String>,
+    // Warning to print before running the command, e.g. when its file is part of an include cycle
+    pub warning: Option<String>,
     // Commands to run after the command finishes
     pub children: Vec<Command>,
 }
@@ -53,32 +116,137 @@ pub struct CommandBuilder {
     pub until: Option<i32>,
     // Environment variables to add to the command
     pub env: HashMap<String, String>,
+    // Environment variables to remove from the environment the command runs with
+    pub env_remove: Vec<String>,
+    // Whether the command only gets the environment variables it sets itself
+    pub env_clear: bool,
+    // Environment variables whose values are replaced with `***` in the output
+    pub secret_env: Vec<String>,
     // The arguments to the command itself, not to std
     pub args: HashMap<String, String>,
-    // Whether to load .env file
-    pub load_dotenv: bool,
+    // Arguments whose values are substituted as is instead of being escaped for the shell
+    pub raw_args: Vec<String>,
+    // Dotenv files to load when the command runs, relative to the file it's defined in
+    pub env_files: Vec<String>,
+    // Variables loaded from dotenv files, which are escaped when substituted into the command
+    pub quoted_env: Vec<String>,
     // Which directory to run the command in
     pub working_dir: String,
-    // The command to run
+    // Directory of the file the command was defined in, the working directory is relative to it
+    pub project_root: String,
+    // The command to run, split into words the way a shell would
     pub command: Vec<String>,
+    // The command as it was written, which is what the shell runs
+    pub command_line: String,
+    // Whether to spawn the program directly instead of running the command in a shell
+    pub exec: bool,
     // Whether or not children should run in parallel
     pub parallel: bool,
+    // How many children may run at the same time when running in parallel
+    pub jobs: Option<usize>,
     // Command to run before all other commands, e.g. to set up environment variables
     pub prefix: Vec<Command>,
     // Command to run after all other commands, e.g. for cleanup
     pub suffix: Vec<Command>,
     // How long to sleep before running
     pub delay: f64,
+    // When and how to run the command again if it fails
+    pub retry: Option<Retry>,
+    // Whether the environment variables written to `$COMMANDER_ENV` are passed on to the
+    // commands after it
+    pub export_env: bool,
+    // Where the exported environment variables and outputs end up, set by whatever runs the
+    // command
+    pub export_sink: Option<ExportSink>,
+    // Name the commands after it use to refer to its outputs
+    pub name: String,
+    // Outputs of the commands before it, as `steps.<name>.outputs.<key>`
+    pub step_outputs: HashMap<String, String>,
+    // Whether to keep the command running in the background while the commands after it run
+    pub service: bool,
+    // When a service is ready for the commands after it, right away if not set
+    pub ready_when: Option<ReadyWhen>,
+    // How many seconds each run of the command may take
+    pub timeout: Option<f64>,
+    // How many seconds the whole command may take, including repeats and children
+    pub total_timeout: Option<f64>,
+    // How many seconds a stopped process gets to exit before it's killed
+    pub kill_grace: f64,
+    // When the command has to be done, inherited from the commands it runs under
+    pub deadline: Option<Instant>,
+    // Whether to stop the other children as soon as one of them fails
+    pub fail_fast: bool,
+    // Stops the command when Ctrl-C is pressed or a sibling fails, inherited like the deadline
+    pub cancellation: process::Cancellation,
     // Which shell to run the command in
     pub shell: String,
-    // File handles that need to stay open for the duration the command exists
-    pub file_handles: Vec<NamedTempFile>,
+    // Error to report instead of running the command, e.g. when it has no variant for the platform
+    pub error: Option<String>,
+    // Warning to print before running the command, e.g. when its file is part of an include cycle
+    pub warning: Option<String>,
     // Commands to run after the command finishes
     pub children: Vec<Command>,
 }
 
 //=============| IMPL |==============//
 
+/// A command with its variables and arguments filled in
+struct Resolved {
+    env: HashMap<String, String>,
+    // Values replaced with `***` in the output
+    secrets: std::sync::Arc<Vec<String>>,
+    working_dir: PathBuf,
+    // What the shell runs
+    command_line: String,
+    // Arguments passed to the shell after the command
+    shell_args: Vec<String>,
+    // What runs without a shell
+    argv: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Retry {
+    // How many times to run the command at most, including the first time
+    pub attempts: i32,
+    // Seconds to wait before the second attempt, doubling for every attempt after it
+    pub backoff: f64,
+    // The most seconds to wait between attempts
+    pub max_delay: Option<f64>,
+    // Whether to wait a random part of the delay, so retries from several commands spread out
+    pub jitter: bool,
+    // Only retry when the command exits with one of these codes, any code if empty
+    pub on_exit_codes: Vec<i32>,
+    // Keep retrying until the output of the command matches
+    pub until_output_matches: Option<regex::Regex>,
+}
+
+#[derive(Debug)]
+pub struct ReadyWhen {
+    // Ready once something accepts connections on the port, either a number or `host:port`
+    pub port: Option<String>,
+    // Ready once a line of output matches
+    pub log_matches: Option<regex::Regex>,
+    // Ready once the file exists, relative to the working directory
+    pub file_exists: Option<String>,
+    // How many seconds to wait for the service to be ready
+    pub timeout: f64,
+}
+
+impl Retry {
+    /// How many seconds to wait after the given attempt
+    pub fn get_delay(&self, attempt: i32) -> f64 {
+        // The delay stops doubling long before it would overflow
+        let mut delay = self.backoff * 2f64.powi((attempt - 1).clamp(0, 32));
+        if let Some(max_delay) = self.max_delay {
+            delay = delay.min(max_delay);
+        }
+        if self.jitter {
+            delay *= 0.5 + fastrand::f64() * 0.5;
+        }
+        delay
+    }
+}
+
 impl Command {
     pub fn builder() -> CommandBuilder {
         CommandBuilder {
@@ -87,91 +255,767 @@ impl Command {
             max_repeat: None,
             until: None,
             env: HashMap::new(),
-            load_dotenv: false,
+            env_remove: vec![],
+            env_clear: false,
+            secret_env: vec![],
+            raw_args: vec![],
+            env_files: vec![],
+            quoted_env: vec![],
             working_dir: "".to_string(),
+            project_root: "".to_string(),
             command: vec![],
+            command_line: "".to_string(),
+            exec: false,
             args: HashMap::new(),
             children: vec![],
             parallel: false,
+            jobs: None,
             prefix: vec![],
             suffix: vec![],
             delay: 0.0,
+            retry: None,
+            export_env: false,
+            export_sink: None,
+            name: "".to_string(),
+            step_outputs: HashMap::new(),
+            service: false,
+            ready_when: None,
+            timeout: None,
+            total_timeout: None,
+            kill_grace: 5.0,
+            deadline: None,
+            fail_fast: false,
+            cancellation: process::Cancellation::default(),
             shell: "".to_string(),
-            file_handles: vec![],
+            error: None,
+            warning: None,
+        }
+    }
+    /// Calls `f` for the command and every command nested in it: its children, prefix and
+    /// suffix commands, and theirs
+    fn for_each_command(&mut self, f: &mut impl FnMut(&mut Command)) {
+        f(self);
+        for command in self
+            .children
+            .iter_mut()
+            .chain(self.prefix.iter_mut())
+            .chain(self.suffix.iter_mut())
+        {
+            command.for_each_command(f);
+        }
+    }
+    /// Sets the directory of the file the command was defined in, which its working directory
+    /// is relative to, for the command and its children unless they already have one
+    pub fn set_base_dir(&mut self, dir: &Path) {
+        let dir = dir.to_string_lossy().to_string();
+        self.for_each_command(&mut |command| {
+            if command.project_root.is_empty() {
+                command.project_root = dir.clone();
+            }
+        });
+    }
+    /// Adds an environment variable to the command and all of its children, unless they
+    /// already set it themselves
+    pub fn add_env(&mut self, key: &str, value: &str) {
+        self.for_each_command(&mut |command| {
+            if !command.env.contains_key(key) {
+                command.env.insert(key.to_string(), value.to_string());
+            }
+        });
+    }
+    /// Adds a variable from a dotenv file to the command and all of its children. It replaces
+    /// variables from other dotenv files, but not the ones the commands set themselves.
+    fn add_dotenv(&mut self, key: &str, value: &str) {
+        self.for_each_command(&mut |command| {
+            let is_dotenv = command.quoted_env.iter().any(|x| x == key);
+            if is_dotenv || !command.env.contains_key(key) {
+                command.env.insert(key.to_string(), value.to_string());
+                if !is_dotenv {
+                    command.quoted_env.push(key.to_string());
+                }
+            }
+        });
+    }
+    /// Reads the command's dotenv files in order, skipping the ones that don't exist. Values can
+    /// use `${NAME}` to refer to variables from earlier lines and files, the command's own `env`
+    /// and the environment. Variables from files ending in `.secret` are marked as secret.
+    fn read_env_files(&self) -> Result<Vec<(String, String, bool)>, std::io::Error> {
+        let project_root = Path::new(&self.project_root);
+        let mut variables = self.env.clone();
+        let mut result: Vec<(String, String, bool)> = vec![];
+        for env_file in &self.env_files {
+            let path = project_root.join(env_file);
+            let is_secret = env_file.ends_with(".secret");
+            let content = match std::fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("Couldn't read {}: {}", path.display(), e),
+                    ))
+                }
+            };
+            for (k, v, expand) in parse_dotenv(&content) {
+                let is_dotenv =
+                    self.quoted_env.contains(&k) || result.iter().any(|(x, _, _)| *x == k);
+                if !is_dotenv && self.env.contains_key(&k) {
+                    continue;
+                }
+                let value = match expand {
+                    true => interpolate(&v, &variables, project_root),
+                    false => v,
+                };
+                variables.insert(k.clone(), value.clone());
+                result.retain(|(x, _, _)| *x != k);
+                result.push((k, value, is_secret));
+            }
+        }
+        Ok(result)
+    }
+    /// A short description of what the command runs, scripts are shown by their first line
+    pub fn get_description(&self) -> String {
+        if self.command.is_empty() {
+            return format!("[{} commands]", self.children.len());
+        }
+        match self.command_line.split_once('\n') {
+            _ if self.exec => self.command_line.replace('\n', "\\n"),
+            Some((first_line, _)) => format!("{} ...", first_line),
+            None => self.command_line.clone(),
+        }
+    }
+    /// Sets an environment variable for the command and all of its children, replacing the
+    /// value they already have
+    pub fn set_env(&mut self, key: &str, value: &str) {
+        self.for_each_command(&mut |command| {
+            command.env.insert(key.to_string(), value.to_string());
+        });
+    }
+    /// Changes the default of an argument for the command and all of its children that take it
+    pub fn set_arg(&mut self, key: &str, value: &str) {
+        self.for_each_command(&mut |command| {
+            if let Some(default) = command.args.get_mut(key) {
+                *default = value.to_string();
+            }
+        });
+    }
+    /// Applies the environment variables and argument defaults of a profile, which win over
+    /// the ones the command sets itself
+    pub fn apply_profile(&mut self, profile: &Command) {
+        for (k, v) in &profile.env {
+            self.set_env(k, v);
+        }
+        for (k, v) in &profile.args {
+            self.set_arg(k, v);
+        }
+    }
+    /// Makes the output of an earlier command available to the command and all of its children
+    pub fn set_step_output(&mut self, key: &str, value: &str) {
+        self.for_each_command(&mut |command| {
+            command
+                .step_outputs
+                .insert(key.to_string(), value.to_string());
+        });
+    }
+    /// The names of the arguments the command and the children it passes its arguments to take
+    fn declared_args(&self) -> HashSet<String> {
+        let mut names: HashSet<String> = self.args.keys().cloned().collect();
+        for child in &self.children {
+            names.extend(child.declared_args());
+        }
+        names
+    }
+    /// Runs the command and returns the environment variables and outputs it passes on to the
+    /// commands after it
+    pub fn execute_exporting(
+        mut self,
+        args: Vec<String>,
+    ) -> Result<(i32, Exported), std::io::Error> {
+        let export_sink: ExportSink = Default::default();
+        self.export_sink = Some(export_sink.clone());
+        let status = self.execute(args)?;
+        let exported = std::mem::take(&mut *export_sink.lock().unwrap());
+        Ok((status, exported))
+    }
+    /// Runs the command, failing with a timeout error if it takes longer than its
+    /// `total_timeout`
+    pub fn execute(mut self, args: Vec<String>) -> Result<i32, std::io::Error> {
+        let total_timeout = self.total_timeout;
+        let own_deadline = total_timeout.map(|x| Instant::now() + Duration::from_secs_f64(x));
+        self.deadline = match (self.deadline, own_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        match self.run(args) {
+            Err(e)
+                if e.kind() == std::io::ErrorKind::TimedOut
+                    && own_deadline.is_some_and(|x| Instant::now() >= x) =>
+            {
+                Err(process::timed_out_error(total_timeout.unwrap()))
+            }
+            result => result,
+        }
+    }
+    /// Prints what the command would run, with its variables and arguments filled in, without
+    /// running anything. Outputs of earlier commands are only known once they ran, so they're
+    /// left empty.
+    pub fn print_plan(mut self, args: Vec<String>, indent: usize) -> Result<(), std::io::Error> {
+        let pad = " ".repeat(indent);
+        if let Some(error) = &self.error {
+            println!("{}error: {}", pad, error);
+            return Ok(());
+        }
+        self.load_environment()?;
+        print_plans(
+            "prefix",
+            std::mem::take(&mut self.prefix),
+            &[],
+            &HashSet::new(),
+            indent,
+        )?;
+
+        if !self.command.is_empty() {
+            let resolved = self.resolve(&args);
+            let secrets = resolved.secrets.clone();
+            let is_script = !self.exec && resolved.command_line.contains('\n');
+            let script = is_script.then(|| Path::new("<script>"));
+            let process = self.new_process(&resolved, script);
+            let argv: Vec<String> = std::iter::once(process.get_program())
+                .chain(process.get_args())
+                .map(|x| quote_arg(&x.to_string_lossy()))
+                .collect();
+            println!("{}run: {}", pad, mask_secrets(&argv.join(" "), &secrets));
+            let shell = if self.exec {
+                "none".to_string()
+            } else if !self.shell.is_empty() {
+                self.shell.clone()
+            } else if cfg!(target_os = "windows") {
+                "cmd (default)".to_string()
+            } else {
+                "sh (default)".to_string()
+            };
+            println!("{}shell: {}", pad, shell);
+            if is_script {
+                println!("{}script:", pad);
+                for line in resolved.command_line.lines() {
+                    println!("{}    {}", pad, mask_secrets(line, &secrets));
+                }
+            }
+            println!("{}working_dir: {}", pad, resolved.working_dir.display());
+
+            // Only the variables that differ from the environment Cargo Commander runs in
+            let mut env: Vec<String> = vec![];
+            if self.env_clear {
+                env.push("(cleared)".to_string());
+            }
+            for (k, v) in process.get_envs() {
+                let k = k.to_string_lossy();
+                match v {
+                    Some(v) if self.env_clear || std::env::var_os(&*k).as_deref() != Some(v) => {
+                        let v = match self.secret_env.iter().any(|x| *x == k) {
+                            true => "***".to_string(),
+                            false => mask_secrets(&v.to_string_lossy(), &secrets),
+                        };
+                        env.push(format!("{}={}", k, v));
+                    }
+                    Some(_) => {}
+                    None => env.push(format!("-{}", k)),
+                }
+            }
+            if !env.is_empty() {
+                env.sort_unstable();
+                println!("{}env: {}", pad, env.join(" "));
+            }
+        }
+
+        let mut settings: Vec<String> = vec![];
+        if let Some(repeat) = self.repeat {
+            settings.push(format!("repeat {}", repeat));
+        }
+        if let Some(max_repeat) = self.max_repeat {
+            settings.push(format!("max_repeat {}", max_repeat));
+        }
+        if let Some(until) = self.until {
+            settings.push(format!("until {}", until));
+        }
+        if self.delay > 0.0 {
+            settings.push(format!("delay {}", process::format_duration(self.delay)));
+        }
+        if let Some(retry) = &self.retry {
+            settings.push(format!("retry {} attempts", retry.attempts));
+        }
+        if let Some(timeout) = self.timeout {
+            settings.push(format!("timeout {}", process::format_duration(timeout)));
+        }
+        if let Some(total_timeout) = self.total_timeout {
+            settings.push(format!(
+                "total timeout {}",
+                process::format_duration(total_timeout)
+            ));
+        }
+        if self.service {
+            settings.push("service".to_string());
+        }
+        if !settings.is_empty() {
+            println!("{}{}", pad, settings.join(", "));
+        }
+
+        if !self.children.is_empty() {
+            let mut label = match self.parallel {
+                true => format!("parallel, {} commands", self.children.len()),
+                false => format!("in order, {} commands", self.children.len()),
+            };
+            if let Some(jobs) = self.jobs.filter(|_| self.parallel) {
+                label.push_str(&format!(", {} at a time", jobs));
+            }
+            if self.fail_fast {
+                label.push_str(", fail fast");
+            }
+            let declared = self.declared_args();
+            let children = std::mem::take(&mut self.children);
+            print_plans(&label, children, &args, &declared, indent)?;
+        }
+        print_plans(
+            "suffix",
+            std::mem::take(&mut self.suffix),
+            &[],
+            &HashSet::new(),
+            indent,
+        )?;
+        Ok(())
+    }
+    /// Loads the command's dotenv files and passes the environment settings on to everything it
+    /// runs
+    fn load_environment(&mut self) -> Result<(), std::io::Error> {
+        // Dotenv files are read when the command runs, so they're relative to the file the command
+        // is defined in and later files override earlier ones
+        for (k, v, is_secret) in self.read_env_files()? {
+            self.add_dotenv(&k, &v);
+            if is_secret && !self.secret_env.contains(&k) {
+                self.secret_env.push(k);
+            }
         }
+
+        // Everything the command runs gets the same cleaned up environment, and keeps the same
+        // values secret
+        let env_clear = self.env_clear;
+        let env_remove = self.env_remove.clone();
+        let secret_env = self.secret_env.clone();
+        self.for_each_command(&mut |command| {
+            command.env_clear |= env_clear;
+            for k in &env_remove {
+                if !command.env_remove.contains(k) {
+                    command.env_remove.push(k.clone());
+                }
+            }
+            for k in &secret_env {
+                if !command.secret_env.contains(k) {
+                    command.secret_env.push(k.clone());
+                }
+            }
+        });
+        Ok(())
     }
-    pub fn execute(self, args: Vec<String>) -> Result<i32, std::io::Error> {
-        let working_dir: String;
-        if self.working_dir != "".to_string() {
-            working_dir = self.working_dir.clone();
+    /// Fills in the variables and arguments of the command, along with the environment and
+    /// working directory it runs with
+    fn resolve(&self, args: &[String]) -> Resolved {
+        let project_root = Path::new(&self.project_root);
+        // Variables from dotenv files were already expanded when they were read. The others can
+        // refer to each other and to the environment Cargo Commander runs in, but never to
+        // themselves, so `PATH = "/opt/bin:${env.PATH}"` extends the real `PATH`.
+        let dotenv: HashMap<String, String> = self
+            .env
+            .iter()
+            .filter(|(k, _)| self.quoted_env.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let mut variables = dotenv.clone();
+        for (k, v) in &self.env {
+            if !dotenv.contains_key(k) {
+                variables.insert(k.clone(), interpolate(v, &dotenv, project_root));
+            }
+        }
+        let mut env: HashMap<String, String> = HashMap::new();
+        for (k, v) in &self.env {
+            let value = match self.quoted_env.contains(k) {
+                true => v.clone(),
+                false => {
+                    let own = variables.remove(k);
+                    let value = interpolate(v, &variables, project_root);
+                    variables.extend(own.map(|x| (k.clone(), x)));
+                    value
+                }
+            };
+            env.insert(k.clone(), value);
+        }
+        // The values of secret variables are replaced with `***` in everything the command prints
+        let secrets = get_secrets(&self.secret_env, &env);
+        let mut working_dir = project_root.join(interpolate(&self.working_dir, &env, project_root));
+        if working_dir.as_os_str().is_empty() {
+            working_dir = PathBuf::from(".");
+        }
+
+        // Named `name=value` arguments override the defaults, everything else is passed on to the
+        // program, and so is everything after `--`. Arguments only the children take are left
+        // out.
+        let declared = self.declared_args();
+        let mut arg_values: HashMap<String, String> = HashMap::new();
+        for (k, v) in &self.args {
+            arg_values.insert(k.clone(), interpolate(v, &env, project_root));
+        }
+        let mut passthrough: Vec<String> = vec![];
+        let mut args_iter = args.iter();
+        while let Some(arg) = args_iter.next() {
+            if arg == "--" {
+                passthrough.extend(args_iter.by_ref().cloned());
+                break;
+            }
+            match arg.split_once('=') {
+                Some((k, v)) if declared.contains(k) => {
+                    if self.args.contains_key(k) {
+                        arg_values.insert(k.to_string(), v.to_string());
+                    }
+                }
+                _ => passthrough.push(arg.clone()),
+            }
+        }
+
+        // Outputs of earlier commands can only be used in the command itself, they're not
+        // environment variables
+        let mut variables = env.clone();
+        variables.extend(self.step_outputs.clone());
+        // Values from the .env file and outputs are escaped like argument values
+        let command_line = interpolate_for_shell(
+            &self.command_line,
+            &variables,
+            project_root,
+            &|name, value| {
+                let name = name.strip_prefix("env.").unwrap_or(name);
+                if self.quoted_env.iter().any(|x| x == name) || name.starts_with("steps.") {
+                    quote_for_shell(&value, &self.shell)
+                } else {
+                    value
+                }
+            },
+        );
+        let mut command_line = substitute_args(
+            &command_line,
+            &arg_values,
+            &self.raw_args,
+            Some(&self.shell),
+        );
+        // Extra arguments replace `${args}`, are available as `$@` in `sh`, and are appended to
+        // the command otherwise. Scripts and programs run without a shell get them as arguments.
+        let mut shell_args: Vec<String> = vec![];
+        let quoted_args: Vec<String> = passthrough
+            .iter()
+            .map(|x| quote_for_shell(x, &self.shell))
+            .collect();
+        if command_line.contains('\n') {
+            shell_args = passthrough.clone();
+        } else if command_line.contains("${args}") {
+            command_line = command_line.replace("${args}", &quoted_args.join(" "));
+        } else if cfg!(not(target_os = "windows"))
+            && self.shell.is_empty()
+            && (command_line.contains("$@") || command_line.contains("$*"))
+        {
+            shell_args = passthrough.clone();
+        } else {
+            for arg in quoted_args {
+                command_line.push(' ');
+                command_line.push_str(&arg);
+            }
+        }
+        let argv: Vec<String> = self
+            .command
+            .iter()
+            .map(|x| interpolate(x, &variables, project_root))
+            .map(|x| substitute_args(&x, &arg_values, &self.raw_args, None))
+            .chain(passthrough.iter().cloned())
+            .collect();
+
+        Resolved {
+            env,
+            secrets,
+            working_dir,
+            command_line,
+            shell_args,
+            argv,
+        }
+    }
+    /// The process that runs the resolved command, multiline commands are run from `script`
+    fn new_process(&self, resolved: &Resolved, script: Option<&Path>) -> std::process::Command {
+        let mut process = if self.exec {
+            let mut process = std::process::Command::new(&resolved.argv[0]);
+            process.args(&resolved.argv[1..]);
+            process
         } else {
-            working_dir = ".".to_string();
+            create_process(
+                &self.shell,
+                &resolved.command_line,
+                script,
+                &resolved.shell_args,
+            )
+        };
+        if self.env_clear {
+            process.env_clear();
+        }
+        process
+            .envs(&resolved.env)
+            .current_dir(&resolved.working_dir);
+        for k in &self.env_remove {
+            process.env_remove(k);
+        }
+        process
+    }
+    fn run(mut self, args: Vec<String>) -> Result<i32, std::io::Error> {
+        if let Some(warning) = &self.warning {
+            println!("{}", warning);
+        }
+        if let Some(error) = self.error {
+            return Err(std::io::Error::other(error));
+        }
+
+        self.load_environment()?;
+
+        // Prefix commands get no arguments, stop the command if they fail, and can export
+        // environment variables to it
+        for mut command in std::mem::take(&mut self.prefix) {
+            command.deadline = self.deadline;
+            command.cancellation = self.cancellation.clone();
+            let (status, exported) = command.execute_exporting(vec![])?;
+            if status != 0 {
+                return Ok(status);
+            }
+            for (k, v) in exported.env {
+                self.set_env(&k, &v);
+            }
+            for (k, v) in exported.outputs {
+                self.set_step_output(&k, &v);
+            }
+        }
+
+        // Variables the command exports are written to a file, like `GITHUB_ENV` works
+        let env_file = match self.export_env {
+            true => Some(NamedTempFile::new()?),
+            false => None,
+        };
+        if let Some(env_file) = &env_file {
+            let path = env_file.path().to_string_lossy().to_string();
+            self.set_env("COMMANDER_ENV", &path);
+        }
+        // Named commands can write `name=value` outputs the commands after them can use
+        let output_file = match self.name.is_empty() {
+            true => None,
+            false => Some(NamedTempFile::new()?),
+        };
+        if let Some(output_file) = &output_file {
+            let path = output_file.path().to_string_lossy().to_string();
+            self.set_env("COMMANDER_OUTPUT", &path);
+        }
+        let mut outputs: Vec<(String, String)> = vec![];
+        let resolved = self.resolve(&args);
+        let secrets = resolved.secrets.clone();
+        let working_dir = resolved.working_dir.clone();
+        let command_line = resolved.command_line.clone();
+
+        // Commands spanning several lines are run as a script from a temporary file
+        let mut script: Option<NamedTempFile> = None;
+        if !self.exec && command_line.contains('\n') {
+            let suffix = if cfg!(target_os = "windows") && self.shell.is_empty() {
+                ".cmd"
+            } else {
+                ""
+            };
+            let mut file = tempfile::Builder::new().suffix(suffix).tempfile()?;
+            file.write_all(command_line.as_bytes())?;
+            file.flush()?;
+            script = Some(file);
+        }
+
+        let new_process = || self.new_process(&resolved, script.as_ref().map(|x| x.path()));
+
+        // Services keep running in the background until everything else is done, the command
+        // finishes once they're ready
+        if self.service {
+            if self.command.is_empty() {
+                return Ok(0);
+            }
+            let mut process = new_process();
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                process.process_group(0);
+            }
+            let ready_when = self.ready_when.as_ref();
+            let log_matches = ready_when.and_then(|x| x.log_matches.clone());
+            if log_matches.is_some() || !secrets.is_empty() {
+                process.stdout(std::process::Stdio::piped());
+                process.stderr(std::process::Stdio::piped());
+            }
+            let mut spawned_child = spawn(&mut process)?;
+            let is_ready =
+                std::sync::Arc::new(std::sync::atomic::AtomicBool::new(ready_when.is_none()));
+            let stdout = spawned_child.stdout.take();
+            let stderr = spawned_child.stderr.take();
+            forward_logs(stdout, log_matches.as_ref(), &is_ready, &secrets, false);
+            forward_logs(stderr, log_matches.as_ref(), &is_ready, &secrets, true);
+            if let Some(ready_when) = ready_when {
+                let result = wait_until_ready(
+                    &mut spawned_child,
+                    ready_when,
+                    &is_ready,
+                    &working_dir,
+                    &self.cancellation,
+                );
+                if let Err(e) = result {
+                    process::stop(&mut spawned_child, false, self.kill_grace)?;
+                    return Err(e);
+                }
+            }
+            process::add_service(spawned_child, script, self.kill_grace);
+            return Ok(0);
         }
 
         let mut exit_status: i32;
         let mut repetitions: i32 = 0;
         let mut successes: i32 = 0;
+        // Whether the last run was stopped by `timeout`
+        let mut timed_out = false;
+        // How every attempt ended and how long it took when retrying
+        let mut attempts: Vec<(String, f64)> = vec![];
 
         loop {
-            if self.command.len() == 0 {
+            if self.command.is_empty() {
                 exit_status = 0;
                 break;
             }
             if self.delay > 0.0 {
-                std::thread::sleep(std::time::Duration::from_secs_f64(self.delay));
+                process::sleep(self.delay, &self.cancellation);
+            }
+            if self.cancellation.is_cancelled() {
+                return Err(self.cancellation.error());
             }
             repetitions += 1;
 
-            let cmd = self.command.clone();
-
-            let spawned_child;
-
-            #[cfg(target_os = "windows")]
-            {
-                use std::os::windows::process::CommandExt;
-                spawned_child = std::process::Command::new("cmd")
-                    .arg("/C")
-                    .raw_arg(cmd.join(" "))
-                    .args(&args)
-                    .envs(&self.env)
-                    .current_dir(&working_dir)
-                    .spawn()
-                    .expect("failed to spawn");
-            }
-            #[cfg(not(target_os = "windows"))]
-            {
-                spawned_child = std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(cmd.join(" "))
-                    .arg(args.join(" "))
-                    .envs(&self.env)
-                    .current_dir(&working_dir)
-                    .spawn()
-                    .expect("failed to spawn");
-            }
-            let output = spawned_child.wait_with_output()?;
-            exit_status = output.status.code().unwrap();
-            println!("{}", String::from_utf8(output.stdout).unwrap());
+            let mut process = new_process();
+            // Processes that can time out or be cancelled get a process group of their own, so
+            // everything they started can be stopped along with them
+            let attempt_deadline = self
+                .timeout
+                .map(|x| Instant::now() + Duration::from_secs_f64(x));
+            let deadline = match (attempt_deadline, self.deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            #[cfg(unix)]
+            if deadline.is_some() || self.cancellation.has_siblings() {
+                use std::os::unix::process::CommandExt;
+                process.process_group(0);
+            }
+            // Output is only captured when it has to be matched or have secrets masked, so
+            // programs still see a terminal otherwise
+            let until_output_matches = self
+                .retry
+                .as_ref()
+                .and_then(|x| x.until_output_matches.as_ref());
+            if until_output_matches.is_some() || !secrets.is_empty() {
+                process.stdout(std::process::Stdio::piped());
+            }
+            if !secrets.is_empty() {
+                process.stderr(std::process::Stdio::piped());
+            }
+            let job = process::acquire_job(&self.cancellation)?;
+            let started = Instant::now();
+            process.env("COMMANDER_ATTEMPT", repetitions.to_string());
+            let mut spawned_child = spawn(&mut process)?;
+            let output_reader = forward_output(spawned_child.stdout.take(), &secrets, false);
+            let error_reader = forward_output(spawned_child.stderr.take(), &secrets, true);
+            exit_status = match process::wait(
+                &mut spawned_child,
+                deadline,
+                self.kill_grace,
+                &self.cancellation,
+            ) {
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    // Running out of the time for the whole command stops it, while a single
+                    // run that takes too long fails like `timeout` does
+                    if self.deadline.is_some_and(|x| Instant::now() >= x) {
+                        return Err(e);
+                    }
+                    println!(
+                        "Stopped after {}",
+                        process::format_duration(self.timeout.unwrap())
+                    );
+                    timed_out = true;
+                    124
+                }
+                Err(e) => return Err(e),
+                Ok(status) => {
+                    timed_out = false;
+                    status
+                }
+            };
+            drop(job);
+            let output = output_reader
+                .map(|x| x.join().unwrap_or_default())
+                .unwrap_or_default();
+            if let Some(error_reader) = error_reader {
+                let _ = error_reader.join();
+            }
+            println!();
+            if let Some(retry) = &self.retry {
+                let succeeded =
+                    exit_status == 0 && until_output_matches.is_none_or(|x| x.is_match(&output));
+                let result = if timed_out {
+                    "timed out".to_string()
+                } else if exit_status == 0 && !succeeded {
+                    "no match".to_string()
+                } else {
+                    format!("exit code {}", exit_status)
+                };
+                attempts.push((result, started.elapsed().as_secs_f64()));
+                if succeeded {
+                    break;
+                }
+                // Output that doesn't match is always retried, `on_exit_codes` only limits
+                // which failing exit codes are
+                let can_retry = exit_status == 0
+                    || retry.on_exit_codes.is_empty()
+                    || retry.on_exit_codes.contains(&exit_status);
+                if exit_status == 0 {
+                    exit_status = 1;
+                }
+                if !can_retry {
+                    break;
+                }
+                if repetitions >= retry.attempts {
+                    print_retry_summary(&attempts);
+                    break;
+                }
+                process::sleep(retry.get_delay(repetitions), &self.cancellation);
+                continue;
+            }
             // handle max_repeat
-            if self.max_repeat.is_some() {
-                if repetitions >= self.max_repeat.unwrap() {
+            if let Some(max_repeat) = self.max_repeat {
+                if repetitions >= max_repeat {
                     break;
                 }
             }
             // handle repeat
-            if self.repeat.is_some() && self.until.is_none() {
-                if repetitions < self.repeat.unwrap() {
+            if let Some(repeat) = self.repeat {
+                if self.until.is_none() && repetitions < repeat {
                     continue;
                 }
             }
             // handle until
-            if self.until.is_some() {
-                if exit_status != self.until.unwrap() {
+            if let Some(until) = self.until {
+                if exit_status != until {
                     continue;
                 } else {
                     successes += 1;
-                    if self.repeat.is_some() {
-                        if successes < self.repeat.unwrap() {
+                    if let Some(repeat) = self.repeat {
+                        if successes < repeat {
                             continue;
                         } else {
                             break;
@@ -190,29 +1034,703 @@ impl Command {
             }
         }
 
-        if self.children.len() > 0 {
+        if timed_out {
+            return Err(process::timed_out_error(self.timeout.unwrap()));
+        }
+
+        if !self.children.is_empty() {
+            // The first child that fails decides the status of the whole command, and timeouts
+            // or interruptions fail it outright. With `fail_fast` the first failure cancels the
+            // children that are still running, or haven't started yet.
+            let mut children_status = 0;
+            let mut children_error: Option<std::io::Error> = None;
+            let failed = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let cancellation = if self.fail_fast {
+                self.cancellation.with_flag(&failed)
+            } else {
+                self.cancellation.clone()
+            };
+            let fail_fast = self.fail_fast;
+            let has_failed = move |result: &Result<i32, std::io::Error>| match result {
+                Ok(status) => *status != 0,
+                Err(e) => !process::is_cancelled_error(e),
+            };
+            let declared = self.declared_args();
+            let mut results: Vec<ChildResult> = vec![];
             if self.parallel {
+                // Processes are limited by the shared job pool, `jobs` limits how many children
+                // of this section run at once on top of that
+                let section_jobs = std::sync::Arc::new(process::JobPool::new(
+                    self.jobs.unwrap_or(self.children.len()),
+                ));
                 let mut handles = vec![];
-                for child in self.children {
-                    let cp = args.clone();
-                    handles.push(std::thread::spawn(|| child.execute(cp)));
+                for mut child in self.children {
+                    child.deadline = self.deadline;
+                    child.cancellation = cancellation.clone();
+                    let description = child.get_description();
+                    let cp = args_for_child(&args, &declared, &child);
+                    let section_jobs = section_jobs.clone();
+                    let failed = failed.clone();
+                    let handle = std::thread::spawn(move || {
+                        let started = Instant::now();
+                        let result = match section_jobs.acquire(&child.cancellation) {
+                            Ok(_job) => child.execute(cp),
+                            Err(e) => Err(e),
+                        };
+                        if has_failed(&result) {
+                            failed.store(true, std::sync::atomic::Ordering::SeqCst);
+                        }
+                        (result, started.elapsed().as_secs_f64())
+                    });
+                    handles.push((description, handle));
                 }
-                for h in handles {
-                    // TODO: Handle status
-                    let _ = h.join();
+                for (description, h) in handles {
+                    match h.join() {
+                        Ok((result, duration)) => {
+                            results.push((description, Some(result), duration))
+                        }
+                        Err(_) => results.push((
+                            description,
+                            Some(Err(std::io::Error::other("command panicked"))),
+                            0.0,
+                        )),
+                    }
                 }
             } else {
-                for child in self.children {
-                    // TODO: Handle status
-                    let _ = child.execute(args.clone());
+                // Variables and outputs of a child are passed on to the children after it
+                let mut exported_env: Vec<(String, String)> = vec![];
+                for mut child in self.children {
+                    let description = child.get_description();
+                    if cancellation.is_cancelled() {
+                        results.push((description, None, 0.0));
+                        continue;
+                    }
+                    child.deadline = self.deadline;
+                    child.cancellation = cancellation.clone();
+                    for (k, v) in &exported_env {
+                        child.set_env(k, v);
+                    }
+                    for (k, v) in &outputs {
+                        child.set_step_output(k, v);
+                    }
+                    let started = Instant::now();
+                    let is_service = child.service;
+                    let child_args = args_for_child(&args, &declared, &child);
+                    let result = match child.execute_exporting(child_args) {
+                        Ok((status, exported)) => {
+                            exported_env.extend(exported.env);
+                            outputs.extend(exported.outputs);
+                            Ok(status)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    // The commands after a service depend on it, so they can't run without it
+                    if is_service && result.is_err() {
+                        return result;
+                    }
+                    if fail_fast && has_failed(&result) {
+                        failed.store(true, std::sync::atomic::Ordering::SeqCst);
+                    }
+                    results.push((description, Some(result), started.elapsed().as_secs_f64()));
+                }
+            }
+            if self.cancellation.is_cancelled() {
+                return Err(self.cancellation.error());
+            }
+            if fail_fast {
+                print_children_summary(&results);
+            }
+            for (_, result, _) in results {
+                match result {
+                    Some(Ok(status)) if children_status == 0 => {
+                        children_status = status;
+                    }
+                    // Children cancelled by `fail_fast` didn't fail themselves, the one that
+                    // stopped them decides the status
+                    Some(Err(e)) if process::is_cancelled_error(&e) => {}
+                    Some(Err(e)) => {
+                        let is_stopped = e.kind() == std::io::ErrorKind::TimedOut
+                            || (e.kind() == std::io::ErrorKind::Interrupted
+                                && !process::is_cancelled_error(&e));
+                        if is_stopped {
+                            children_error = children_error.or(Some(e));
+                            continue;
+                        }
+                        // The summary already shows why children failed with `fail_fast`
+                        if !fail_fast {
+                            println!("error: {}", e);
+                        }
+                        if children_status == 0 {
+                            children_status = 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(e) = children_error {
+                return Err(e);
+            }
+            if exit_status == 0 {
+                exit_status = children_status;
+            }
+        }
+
+        if let Some(output_file) = &output_file {
+            for (k, v) in read_env_file(output_file.path()) {
+                outputs.push((format!("steps.{}.outputs.{}", self.name, k), v));
+            }
+        }
+
+        // Suffix commands only run once everything else has succeeded
+        if exit_status == 0 {
+            for mut command in std::mem::take(&mut self.suffix) {
+                command.deadline = self.deadline;
+                command.cancellation = self.cancellation.clone();
+                for (k, v) in &outputs {
+                    command.set_step_output(k, v);
+                }
+                let status = command.execute(vec![])?;
+                if status != 0 {
+                    return Ok(status);
                 }
             }
         }
 
+        if let Some(export_sink) = &self.export_sink {
+            let mut export_sink = export_sink.lock().unwrap();
+            if let Some(env_file) = &env_file {
+                export_sink.env.extend(read_env_file(env_file.path()));
+            }
+            export_sink.outputs.extend(outputs);
+        }
+
         Ok(exit_status)
     }
 }
 
+/// Prints the plans of a list of commands under a label, each one named by its `name` or what
+/// it runs
+fn print_plans(
+    label: &str,
+    commands: Vec<Command>,
+    args: &[String],
+    declared: &HashSet<String>,
+    indent: usize,
+) -> Result<(), std::io::Error> {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let pad = " ".repeat(indent);
+    println!("{}{}:", pad, label);
+    for command in commands {
+        match command.name.is_empty() {
+            true => println!("{}  - {}", pad, command.get_description()),
+            false => println!("{}  - {}", pad, command.name),
+        }
+        let command_args = args_for_child(args, declared, &command);
+        command.print_plan(command_args, indent + 6)?;
+    }
+    Ok(())
+}
+
+/// The arguments a child gets from its parent. Named arguments declared anywhere below the parent
+/// are only passed to the children that take them, so they never end up as extra arguments of
+/// another program.
+fn args_for_child(args: &[String], declared: &HashSet<String>, child: &Command) -> Vec<String> {
+    let child_declared = child.declared_args();
+    let mut child_args: Vec<String> = vec![];
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        if arg == "--" {
+            child_args.push(arg.clone());
+            child_args.extend(args_iter.by_ref().cloned());
+            break;
+        }
+        match arg.split_once('=') {
+            Some((k, _)) if declared.contains(k) && !child_declared.contains(k) => {}
+            _ => child_args.push(arg.clone()),
+        }
+    }
+    child_args
+}
+
+/// Reads `KEY=VALUE` lines, skipping empty lines and comments
+fn read_env_file(path: &Path) -> Vec<(String, String)> {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .filter_map(|x| x.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.to_string()))
+        .collect()
+}
+
+/// Parses the lines of a dotenv file into keys and values, along with whether the value may
+/// refer to other variables. Lines can start with `export`, values can be in double quotes,
+/// which support `\n` and are expanded, or single quotes, which are taken literally, and
+/// unquoted values end at a ` #` comment.
+fn parse_dotenv(content: &str) -> Vec<(String, String, bool)> {
+    let mut result = vec![];
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_string(), value.trim_start()),
+            None => continue,
+        };
+        let quote = match value.chars().next() {
+            Some(x) if x == '"' || x == '\'' => x,
+            _ => {
+                let value = match value.find(" #") {
+                    Some(i) => &value[..i],
+                    None => value,
+                };
+                result.push((key, value.trim_end().to_string(), true));
+                continue;
+            }
+        };
+        // Quoted values can span several lines
+        let mut value = value[1..].to_string();
+        let mut end = find_closing_quote(&value, quote);
+        while end.is_none() {
+            match lines.next() {
+                Some(line) => {
+                    value.push('\n');
+                    value.push_str(line);
+                    end = find_closing_quote(&value, quote);
+                }
+                None => break,
+            }
+        }
+        if let Some(end) = end {
+            value.truncate(end);
+        }
+        if quote == '"' {
+            value = value.replace("\\n", "\n").replace("\\\"", "\"");
+        }
+        result.push((key, value, quote == '"'));
+    }
+    result
+}
+
+/// Where a quoted dotenv value ends, double quotes can be escaped with a backslash
+fn find_closing_quote(value: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, x) in value.char_indices() {
+        match x {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            x if x == quote && !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// The values of the secret variables, either from the command's environment or the one Cargo
+/// Commander runs in, longest first so a secret that contains another one is masked as a whole
+fn get_secrets(
+    secret_env: &[String],
+    env: &HashMap<String, String>,
+) -> std::sync::Arc<Vec<String>> {
+    let mut secrets: Vec<String> = secret_env
+        .iter()
+        .filter_map(|x| env.get(x).cloned().or_else(|| std::env::var(x).ok()))
+        .filter(|x| !x.is_empty())
+        .collect();
+    secrets.sort_unstable_by_key(|x| std::cmp::Reverse(x.len()));
+    secrets.dedup();
+    std::sync::Arc::new(secrets)
+}
+
+/// Replaces every secret in the text with `***`
+fn mask_secrets(text: &str, secrets: &[String]) -> String {
+    let mut text = text.to_string();
+    for secret in secrets {
+        text = text.replace(secret.as_str(), "***");
+    }
+    text
+}
+
+/// Prints the output of a process line by line as it comes, with its secrets masked, and
+/// returns everything it printed as it was written
+fn forward_output<R: std::io::Read + Send + 'static>(
+    output: Option<R>,
+    secrets: &std::sync::Arc<Vec<String>>,
+    is_stderr: bool,
+) -> Option<std::thread::JoinHandle<String>> {
+    let output = output?;
+    let secrets = secrets.clone();
+    Some(std::thread::spawn(move || {
+        let mut result = String::new();
+        for_each_line(output, |line| {
+            let masked = mask_secrets(&line, &secrets);
+            if is_stderr {
+                eprintln!("{}", masked);
+            } else {
+                println!("{}", masked);
+            }
+            result.push_str(&line);
+            result.push('\n');
+        });
+        result
+    }))
+}
+
+/// Prints the output of a service as it comes with its secrets masked, marking it as ready
+/// once a line matches
+fn forward_logs<R: std::io::Read + Send + 'static>(
+    output: Option<R>,
+    log_matches: Option<&regex::Regex>,
+    is_ready: &std::sync::Arc<std::sync::atomic::AtomicBool>,
+    secrets: &std::sync::Arc<Vec<String>>,
+    is_stderr: bool,
+) {
+    let output = match output {
+        Some(output) => output,
+        None => return,
+    };
+    let log_matches = log_matches.cloned();
+    let is_ready = is_ready.clone();
+    let secrets = secrets.clone();
+    std::thread::spawn(move || {
+        for_each_line(output, |line| {
+            let masked = mask_secrets(&line, &secrets);
+            if is_stderr {
+                eprintln!("{}", masked);
+            } else {
+                println!("{}", masked);
+            }
+            if log_matches.as_ref().is_some_and(|x| x.is_match(&line)) {
+                is_ready.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+    });
+}
+
+/// Calls `f` with every line of the output until it's closed. Invalid UTF-8 is replaced rather
+/// than ending the output early, which would leave the process writing to a closed pipe.
+fn for_each_line<R: std::io::Read>(output: R, mut f: impl FnMut(String)) {
+    let mut reader = std::io::BufReader::new(output);
+    let mut buffer: Vec<u8> = vec![];
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if buffer.ends_with(b"\n") {
+            buffer.pop();
+            if buffer.ends_with(b"\r") {
+                buffer.pop();
+            }
+        }
+        f(String::from_utf8_lossy(&buffer).into_owned());
+    }
+}
+
+/// Waits until the readiness probe of a service passes, failing if the service exits, takes
+/// too long or is cancelled first
+fn wait_until_ready(
+    child: &mut std::process::Child,
+    ready_when: &ReadyWhen,
+    is_ready: &std::sync::atomic::AtomicBool,
+    working_dir: &Path,
+    cancellation: &process::Cancellation,
+) -> Result<(), std::io::Error> {
+    let deadline = Instant::now() + Duration::from_secs_f64(ready_when.timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "service exited before it was ready ({})",
+                status
+            )));
+        }
+        let mut ready =
+            ready_when.log_matches.is_none() || is_ready.load(std::sync::atomic::Ordering::SeqCst);
+        if let Some(port) = &ready_when.port {
+            let address = if port.contains(':') {
+                port.clone()
+            } else {
+                format!("127.0.0.1:{}", port)
+            };
+            let addresses = std::net::ToSocketAddrs::to_socket_addrs(&address)?;
+            ready &= addresses.into_iter().any(|x| {
+                std::net::TcpStream::connect_timeout(&x, Duration::from_millis(200)).is_ok()
+            });
+        }
+        if let Some(file) = &ready_when.file_exists {
+            ready &= working_dir.join(file).exists();
+        }
+        if ready {
+            return Ok(());
+        }
+        if cancellation.is_cancelled() {
+            return Err(cancellation.error());
+        }
+        if Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "service wasn't ready after {}",
+                    process::format_duration(ready_when.timeout)
+                ),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The description of a child along with how it ended, if it ran, and how long it took
+type ChildResult = (String, Option<Result<i32, std::io::Error>>, f64);
+
+/// Prints how every child of a `fail_fast` command ended and how long it took
+fn print_children_summary(results: &[ChildResult]) {
+    println!("Summary:");
+    for (description, result, duration) in results {
+        let (state, detail) = match result {
+            Some(Ok(0)) => ("succeeded", "".to_string()),
+            Some(Ok(status)) => ("failed", format!(" (exit code {})", status)),
+            Some(Err(e)) if process::is_cancelled_error(e) => ("cancelled", "".to_string()),
+            Some(Err(e)) => ("failed", format!(" ({})", e)),
+            None => ("cancelled", " (not started)".to_string()),
+        };
+        println!(
+            "    {:<10} {:>7.2}s  {}{}",
+            state, duration, description, detail
+        );
+    }
+}
+
+fn print_retry_summary(attempts: &[(String, f64)]) {
+    println!("Giving up after {} attempts:", attempts.len());
+    for (i, (result, duration)) in attempts.iter().enumerate() {
+        println!("    attempt {:<3} {:<14} {:.2}s", i + 1, result, duration);
+    }
+}
+
+/// Creates the process running a command line, or a script file, in the given shell. Without
+/// a shell it's `sh -c` on unix and `cmd /C` on windows.
+fn create_process(
+    shell: &str,
+    command_line: &str,
+    script: Option<&Path>,
+    shell_args: &[String],
+) -> std::process::Command {
+    let shell = split_words(shell);
+    if let Some((program, shell_flags)) = shell.split_first() {
+        let mut process = std::process::Command::new(program);
+        process.args(shell_flags);
+        match script {
+            Some(script) => process.arg(script),
+            None => process.arg(command_line),
+        };
+        process.args(shell_args);
+        return process;
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        let mut process = std::process::Command::new("cmd");
+        process.arg("/C");
+        match script {
+            Some(script) => process.arg(script),
+            None => process.raw_arg(command_line),
+        };
+        process.args(shell_args);
+        process
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let mut process = std::process::Command::new("sh");
+        match script {
+            Some(script) => process.arg(script),
+            // The arguments after the command start at `$0`, which is conventionally the shell
+            None => process.arg("-c").arg(command_line).arg("sh"),
+        };
+        process.args(shell_args);
+        process
+    }
+}
+
+/// Starts a process, naming the program when it can't be started since without a shell nothing
+/// else reports that it doesn't exist
+fn spawn(process: &mut std::process::Command) -> Result<std::process::Child, std::io::Error> {
+    process.spawn().map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "failed to run `{}`: {}",
+                process.get_program().to_string_lossy(),
+                e
+            ),
+        )
+    })
+}
+
+/// Splits a string into words the way a POSIX shell does. Single quotes keep everything
+/// literal, double quotes allow escaping `"`, `\`, `$` and `` ` ``, and a backslash outside of
+/// quotes escapes the next character.
+pub fn split_words(s: &str) -> Vec<String> {
+    let mut words: Vec<String> = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.peek() {
+                            Some('"' | '\\' | '$' | '`') => word.push(chars.next().unwrap()),
+                            Some('\n') => {
+                                chars.next();
+                            }
+                            _ => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // A backslash at the end of a line continues the command on the next one
+                Some('\n') => {}
+                Some(c) => {
+                    in_word = true;
+                    word.push(c);
+                }
+                None => {
+                    in_word = true;
+                    word.push('\\');
+                }
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Replaces `$name` with the value of each argument, longest names first so `$name` doesn't
+/// replace the start of `$name2`. Values are escaped for the shell, unless the argument is raw
+/// or `shell` is `None` because the program is run without one.
+fn substitute_args(
+    s: &str,
+    args: &HashMap<String, String>,
+    raw_args: &[String],
+    shell: Option<&str>,
+) -> String {
+    let mut names: Vec<&String> = args.keys().collect();
+    names.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    let mut result = s.to_string();
+    for name in names {
+        let value = match shell {
+            Some(shell) if !raw_args.contains(name) => quote_for_shell(&args[name], shell),
+            _ => args[name].clone(),
+        };
+        result = result.replace(&format!("${}", name), &value);
+    }
+    result
+}
+
+/// Quotes an argument so the shell passes it on as a single, literal argument
+pub fn quote_arg(arg: &str) -> String {
+    quote_for_shell(arg, "")
+}
+
+/// Quotes an argument for the shell a command runs in, where an empty shell is the default one
+/// for the platform. Programs that aren't a known shell, like `python`, get the argument as is.
+pub fn quote_for_shell(arg: &str, shell: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || "-_=+.,/:@".contains(x));
+    let program = split_words(shell)
+        .first()
+        .and_then(|x| Path::new(x).file_stem())
+        .map(|x| x.to_string_lossy().to_lowercase());
+    let program = match program {
+        Some(program) => program,
+        None if cfg!(target_os = "windows") => "cmd".to_string(),
+        None => "sh".to_string(),
+    };
+    match program.as_str() {
+        _ if is_safe => arg.to_string(),
+        // The argument is quoted the way programs split their command line first. Then every
+        // character cmd treats specially is escaped with `^`, quotes included, so cmd never sees
+        // a quoted part in which `%VAR%` would still be expanded.
+        "cmd" => quote_for_windows(arg)
+            .chars()
+            .fold(String::new(), |mut result, x| {
+                if "()%!^\"<>&|".contains(x) {
+                    result.push('^');
+                }
+                result.push(x);
+                result
+            }),
+        "powershell" | "pwsh" => format!("'{}'", arg.replace('\'', "''")),
+        "sh" | "bash" | "zsh" | "dash" | "ksh" => {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+        // Backslashes escape backslashes and quotes even within single quotes in fish
+        "fish" => format!("'{}'", arg.replace('\\', "\\\\").replace('\'', "\\'")),
+        _ => arg.to_string(),
+    }
+}
+
+/// Quotes an argument the way Windows programs split their command line into arguments, where
+/// backslashes are only special in front of a quote
+fn quote_for_windows(arg: &str) -> String {
+    let mut result = String::from("\"");
+    let mut backslashes = 0;
+    for x in arg.chars() {
+        match x {
+            '\\' => backslashes += 1,
+            '"' => {
+                result.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                result.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if x != '\\' {
+            result.push(x);
+        }
+    }
+    result.push_str(&"\\".repeat(backslashes * 2));
+    result.push('"');
+    result
+}
+
 impl CommandBuilder {
     pub fn build(self) -> Command {
         Command {
@@ -221,17 +1739,40 @@ impl CommandBuilder {
             max_repeat: self.max_repeat,
             until: self.until,
             env: self.env,
-            load_dotenv: self.load_dotenv,
+            env_remove: self.env_remove,
+            env_clear: self.env_clear,
+            secret_env: self.secret_env,
+            raw_args: self.raw_args,
+            env_files: self.env_files,
+            quoted_env: self.quoted_env,
             working_dir: self.working_dir,
+            project_root: self.project_root,
             command: self.command,
+            command_line: self.command_line,
+            exec: self.exec,
             args: self.args,
             children: self.children,
             parallel: self.parallel,
+            jobs: self.jobs,
             prefix: self.prefix,
             suffix: self.suffix,
             delay: self.delay,
+            retry: self.retry,
+            export_env: self.export_env,
+            export_sink: self.export_sink,
+            name: self.name,
+            step_outputs: self.step_outputs,
+            service: self.service,
+            ready_when: self.ready_when,
+            timeout: self.timeout,
+            total_timeout: self.total_timeout,
+            kill_grace: self.kill_grace,
+            deadline: self.deadline,
+            fail_fast: self.fail_fast,
+            cancellation: self.cancellation,
             shell: self.shell,
-            file_handles: self.file_handles,
+            error: self.error,
+            warning: self.warning,
         }
     }
 }
@@ -256,14 +1797,96 @@ impl From<&String> for Command {
     }
 }
 
+/// Platforms that commands can have variants for
+pub const PLATFORMS: [&str; 4] = ["unix", "linux", "macos", "windows"];
+
+/// Returns the platforms whose variants apply, most specific first. The detected platform can
+/// be overridden with `COMMANDER_PLATFORM`, e.g. to try the windows variants on linux.
+pub fn get_platforms() -> Vec<String> {
+    platforms_for(std::env::var("COMMANDER_PLATFORM").ok())
+}
+
+/// The platforms whose variants apply on the given platform, or the detected one if it's `None`
+fn platforms_for(platform: Option<String>) -> Vec<String> {
+    let (os, is_unix) = match platform {
+        Some(platform) => {
+            let is_unix = platform != "windows";
+            (platform, is_unix)
+        }
+        None => (std::env::consts::OS.to_string(), cfg!(unix)),
+    };
+    let mut platforms = vec![os];
+    if is_unix && platforms[0] != "unix" {
+        platforms.push("unix".to_string());
+    }
+    platforms
+}
+
+/// Reads a duration written as a number of seconds or as a string like `30s`
+fn duration_value(value: &toml::Value) -> Option<f64> {
+    match value {
+        toml::Value::String(s) => process::parse_duration(s),
+        toml::Value::Integer(i) => process::valid_seconds(*i as f64),
+        toml::Value::Float(f) => process::valid_seconds(*f),
+        _ => None,
+    }
+}
+
+/// Checks whether a table only contains platform variants, e.g. `{ unix = "...", windows = "..." }`
+pub fn is_variant_table(table: &toml::value::Table) -> bool {
+    !table.is_empty() && table.keys().all(|x| PLATFORMS.contains(&x.as_str()))
+}
+
+/// Checks whether a table only contains `[command.<platform>]` overrides, which makes it a
+/// command. Tables whose platform keys aren't tables are sections, e.g. `build.linux = "..."`.
+pub fn is_platform_override_table(table: &toml::value::Table) -> bool {
+    is_variant_table(table) && table.values().all(|x| x.is_table())
+}
+
+/// Merges the `[command.<platform>]` tables that apply over the command itself, so a
+/// platform can override any of its fields
+fn apply_platform_overrides(v: &toml::Value, platforms: &[String]) -> toml::Value {
+    let table = match v {
+        toml::Value::Table(t) => t,
+        _ => return v.clone(),
+    };
+    let mut merged = toml::value::Table::new();
+    for (k, v) in table {
+        if !(PLATFORMS.contains(&k.as_str()) && v.is_table()) {
+            merged.insert(k.clone(), v.clone());
+        }
+    }
+    for platform in platforms.iter().rev() {
+        if let Some(toml::Value::Table(overrides)) = table.get(platform) {
+            for (k, v) in overrides {
+                merged.insert(k.clone(), v.clone());
+            }
+        }
+    }
+    toml::Value::Table(merged)
+}
+
 impl From<&toml::Value> for Command {
     fn from(v: &toml::Value) -> Self {
+        Command::for_platforms(v, &get_platforms())
+    }
+}
+
+impl Command {
+    /// Parses a command, using the variants and overrides of the given platforms, the most
+    /// specific one first
+    fn for_platforms(v: &toml::Value, platforms: &[String]) -> Command {
         let mut command = Command::builder();
+        let has_variants = v.as_table().is_some_and(|t| {
+            t.iter()
+                .any(|(k, v)| PLATFORMS.contains(&k.as_str()) && v.is_table())
+        });
+        let v = &apply_platform_overrides(v, platforms);
 
         match v {
             toml::Value::String(s) => {
-                let parts: Vec<String> = s.split(' ').map(|x| x.to_string()).collect();
-                command.command = parts.clone()
+                command.command_line = s.clone();
+                command.command = split_words(s);
             }
             toml::Value::Integer(_) => {}
             toml::Value::Float(_) => {}
@@ -271,7 +1894,7 @@ impl From<&toml::Value> for Command {
             toml::Value::Datetime(_) => {}
             toml::Value::Array(a) => {
                 for n in a {
-                    command.children.push(Command::from(n));
+                    command.children.push(Command::for_platforms(n, platforms));
                 }
             }
             toml::Value::Table(_) => {}
@@ -282,10 +1905,10 @@ impl From<&toml::Value> for Command {
             match repeat {
                 toml::Value::String(_) => {}
                 toml::Value::Integer(i) => {
-                    command.repeat = Some(i.clone() as i32);
+                    command.repeat = Some(*i as i32);
                 }
                 toml::Value::Float(f) => {
-                    command.repeat = Some(f.clone() as i32);
+                    command.repeat = Some(*f as i32);
                 }
                 toml::Value::Boolean(_) => {}
                 toml::Value::Datetime(_) => {}
@@ -297,8 +1920,8 @@ impl From<&toml::Value> for Command {
             let max_repeat = v.get("max_repeat").unwrap();
             match max_repeat {
                 toml::Value::String(_) => {}
-                toml::Value::Integer(i) => command.max_repeat = Some(i.clone() as i32),
-                toml::Value::Float(f) => command.max_repeat = Some(f.clone() as i32),
+                toml::Value::Integer(i) => command.max_repeat = Some(*i as i32),
+                toml::Value::Float(f) => command.max_repeat = Some(*f as i32),
                 toml::Value::Boolean(_) => {}
                 toml::Value::Datetime(_) => {}
                 toml::Value::Array(_) => {}
@@ -310,10 +1933,10 @@ impl From<&toml::Value> for Command {
             match until {
                 toml::Value::String(_) => {}
                 toml::Value::Integer(i) => {
-                    command.until = Some(i.clone() as i32);
+                    command.until = Some(*i as i32);
                 }
                 toml::Value::Float(f) => {
-                    command.until = Some(f.clone() as i32);
+                    command.until = Some(*f as i32);
                 }
                 toml::Value::Boolean(_) => {}
                 toml::Value::Datetime(_) => {}
@@ -331,15 +1954,67 @@ impl From<&toml::Value> for Command {
                 toml::Value::Datetime(_) => {}
                 toml::Value::Array(a) => {
                     for n in a {
-                        if n.is_str() {
-                            let x = n.as_str().unwrap();
-                            let y: Vec<String> = x.split("=").map(|f| f.to_string()).collect();
-                            if y.len() == 2 {
-                                command.env.insert(y[0].clone(), y[1].clone());
-                            }
+                        // Only the first `=` separates the name, values can contain more
+                        if let Some((k, v)) = n.as_str().and_then(|x| x.split_once('=')) {
+                            command.env.insert(k.to_string(), v.to_string());
                         }
                     }
                 }
+                toml::Value::Table(t) => {
+                    // `{ DATABASE_URL = "postgres://localhost/db", PORT = 8080 }`
+                    for (k, v) in t {
+                        let value = match v.as_str() {
+                            Some(s) => s.to_string(),
+                            None => v.to_string(),
+                        };
+                        command.env.insert(k.clone(), value);
+                    }
+                }
+            }
+        }
+        if v.get("env_remove").is_some() {
+            let env_remove = v.get("env_remove").unwrap();
+            match env_remove {
+                toml::Value::String(s) => command.env_remove.push(s.clone()),
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(a) => {
+                    for n in a.iter().filter_map(|x| x.as_str()) {
+                        command.env_remove.push(n.to_string());
+                    }
+                }
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("secret_env").is_some() {
+            let secret_env = v.get("secret_env").unwrap();
+            match secret_env {
+                toml::Value::String(s) => command.secret_env.push(s.clone()),
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(a) => {
+                    for n in a.iter().filter_map(|x| x.as_str()) {
+                        command.secret_env.push(n.to_string());
+                    }
+                }
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("env_clear").is_some() {
+            let env_clear = v.get("env_clear").unwrap();
+            match env_clear {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(b) => {
+                    command.env_clear = *b;
+                }
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
                 toml::Value::Table(_) => {}
             }
         }
@@ -361,52 +2036,30 @@ impl From<&toml::Value> for Command {
                             } else if y.len() == 2 {
                                 command.args.insert(y[0].clone(), y[1].clone());
                             }
-                        }
-                    }
-
-                    let mut oargs: Vec<String> = std::env::args().collect();
-                    if oargs.len() > 1 {
-                        if oargs[0].contains("cargo-cmd") && oargs[1] == "cmd".to_string() {
-                            // Removes possible cargo run part
-                            oargs.remove(1);
-                        }
-                    }
-                    // Removes the command path
-                    oargs.remove(0);
-
-                    let mut command_args: Vec<String> = vec![];
-                    let mut all_found = false;
-                    while oargs.len() > 0 {
-                        if all_found {
-                            command_args.push(oargs.remove(0));
-                        } else {
-                            if oargs[0].starts_with("-") {
-                                if oargs[0] == "-f" || oargs[0] == "--file" {
-                                    oargs.remove(0);
-                                    oargs.remove(0);
-                                } else {
-                                    all_found = true;
+                        } else if let Some(t) = n.as_table() {
+                            // `{ name = "flags", default = "--release", raw = true }`
+                            if let Some(name) = t.get("name").and_then(|x| x.as_str()) {
+                                let default = t.get("default").and_then(|x| x.as_str());
+                                command
+                                    .args
+                                    .insert(name.to_string(), default.unwrap_or("").to_string());
+                                if t.get("raw").and_then(|x| x.as_bool()) == Some(true) {
+                                    command.raw_args.push(name.to_string());
                                 }
-                            } else {
-                                all_found = true;
                             }
                         }
                     }
-                    command_args.remove(0);
-                    let oargs = command_args;
-
-                    for a in oargs {
-                        let v: Vec<String> = a.split("=").map(|x| x.to_string()).collect();
-                        if v.len() == 2 {
-                            // Ändra här
-                            if command.args.get(&v[0]).is_some() {
-                                let x = command.args.get_mut(&v[0]).unwrap();
-                                *x = v[1].clone();
-                            }
-                        }
+                }
+                toml::Value::Table(t) => {
+                    // `{ target = "dev", port = 8080 }`
+                    for (k, v) in t {
+                        let value = match v.as_str() {
+                            Some(s) => s.to_string(),
+                            None => v.to_string(),
+                        };
+                        command.args.insert(k.clone(), value);
                     }
                 }
-                toml::Value::Table(_) => {}
             }
         }
         if v.get("load_dotenv").is_some() {
@@ -416,14 +2069,8 @@ impl From<&toml::Value> for Command {
                 Value::Integer(_) => {}
                 Value::Float(_) => {}
                 Value::Boolean(b) => {
-                    command.load_dotenv = b.clone();
-                    if *b {
-                        let result: Vec<(String, String)> = dotenv::vars().collect();
-                        for (k, v) in result {
-                            if !command.env.contains_key(k.as_str()) {
-                                command.env.insert(k, v);
-                            }
-                        }
+                    if *b && !command.env_files.iter().any(|x| x == ".env") {
+                        command.env_files.insert(0, ".env".to_string());
                     }
                 }
                 Value::Datetime(_) => {}
@@ -431,6 +2078,22 @@ impl From<&toml::Value> for Command {
                 Value::Table(_) => {}
             }
         }
+        if v.get("env_file").is_some() {
+            let env_file = v.get("env_file").unwrap();
+            match env_file {
+                Value::String(s) => command.env_files.push(s.clone()),
+                Value::Integer(_) => {}
+                Value::Float(_) => {}
+                Value::Boolean(_) => {}
+                Value::Datetime(_) => {}
+                Value::Array(a) => {
+                    for n in a.iter().filter_map(|x| x.as_str()) {
+                        command.env_files.push(n.to_string());
+                    }
+                }
+                Value::Table(_) => {}
+            }
+        }
         if v.get("working_dir").is_some() {
             let working_dir = v.get("working_dir").unwrap();
             match working_dir {
@@ -452,8 +2115,36 @@ impl From<&toml::Value> for Command {
                 toml::Value::Integer(_) => {}
                 toml::Value::Float(_) => {}
                 toml::Value::Boolean(b) => {
-                    command.parallel = b.clone();
+                    command.parallel = *b;
+                }
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("fail_fast").is_some() {
+            let fail_fast = v.get("fail_fast").unwrap();
+            match fail_fast {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(b) => {
+                    command.fail_fast = *b;
+                }
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("jobs").is_some() {
+            let jobs = v.get("jobs").unwrap();
+            match jobs {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(i) => {
+                    command.jobs = Some((*i).max(1) as usize);
                 }
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
                 toml::Value::Datetime(_) => {}
                 toml::Value::Array(_) => {}
                 toml::Value::Table(_) => {}
@@ -464,24 +2155,234 @@ impl From<&toml::Value> for Command {
             match delay {
                 toml::Value::String(_) => {}
                 toml::Value::Integer(i) => {
-                    command.delay = i.clone() as f64;
+                    command.delay = *i as f64;
+                }
+                toml::Value::Float(f) => {
+                    command.delay = *f;
+                }
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("service").is_some() {
+            let service = v.get("service").unwrap();
+            match service {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(b) => {
+                    command.service = *b;
+                }
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(_) => {}
+            }
+        }
+        if v.get("ready_when").is_some() {
+            let ready_when = v.get("ready_when").unwrap();
+            match ready_when {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(t) => {
+                    let log_matches = match t.get("log_matches") {
+                        Some(toml::Value::String(s)) => match regex::Regex::new(s) {
+                            Ok(regex) => Some(regex),
+                            Err(e) => {
+                                command.error = Some(format!("Invalid log_matches: {}", e));
+                                None
+                            }
+                        },
+                        _ => None,
+                    };
+                    let timeout = match t.get("timeout") {
+                        Some(timeout) => match duration_value(timeout) {
+                            Some(timeout) => Some(timeout),
+                            None => {
+                                command.error =
+                                    Some(format!("Invalid ready_when timeout: {}", timeout));
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    command.ready_when = Some(ReadyWhen {
+                        port: match t.get("port") {
+                            Some(toml::Value::Integer(i)) => Some(i.to_string()),
+                            Some(toml::Value::String(s)) => Some(s.clone()),
+                            _ => None,
+                        },
+                        log_matches,
+                        file_exists: t
+                            .get("file_exists")
+                            .and_then(|x| x.as_str())
+                            .map(|x| x.to_string()),
+                        timeout: timeout.unwrap_or(60.0),
+                    });
+                }
+            }
+        }
+        if v.get("retry").is_some() {
+            let retry = v.get("retry").unwrap();
+            match retry {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(i) => {
+                    command.retry = Some(Retry {
+                        attempts: *i as i32,
+                        backoff: 1.0,
+                        max_delay: None,
+                        jitter: false,
+                        on_exit_codes: vec![],
+                        until_output_matches: None,
+                    });
+                }
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(t) => {
+                    let mut seconds = |key: &str| {
+                        let value = t.get(key)?;
+                        let seconds = duration_value(value);
+                        if seconds.is_none() {
+                            command.error = Some(format!("Invalid retry {}: {}", key, value));
+                        }
+                        seconds
+                    };
+                    let backoff = seconds("backoff");
+                    let max_delay = seconds("max_delay");
+                    let until_output_matches = match t.get("until_output_matches") {
+                        Some(toml::Value::String(s)) => {
+                            match regex::RegexBuilder::new(s).multi_line(true).build() {
+                                Ok(regex) => Some(regex),
+                                Err(e) => {
+                                    command.error =
+                                        Some(format!("Invalid until_output_matches: {}", e));
+                                    None
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
+                    command.retry = Some(Retry {
+                        attempts: t.get("attempts").and_then(|x| x.as_integer()).unwrap_or(3)
+                            as i32,
+                        backoff: backoff.unwrap_or(1.0),
+                        max_delay,
+                        jitter: t.get("jitter").and_then(|x| x.as_bool()).unwrap_or(false),
+                        on_exit_codes: t
+                            .get("on_exit_codes")
+                            .and_then(|x| x.as_array())
+                            .map(|x| {
+                                x.iter()
+                                    .filter_map(|x| x.as_integer())
+                                    .map(|x| x as i32)
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        until_output_matches,
+                    });
+                }
+            }
+        }
+        if v.get("timeout").is_some() {
+            let timeout = v.get("timeout").unwrap();
+            match timeout {
+                toml::Value::String(s) => {
+                    command.timeout = process::parse_duration(s);
+                    command.total_timeout = command.timeout;
+                }
+                toml::Value::Integer(i) => {
+                    command.timeout = process::valid_seconds(*i as f64);
+                    command.total_timeout = command.timeout;
                 }
                 toml::Value::Float(f) => {
-                    command.delay = f.clone();
+                    command.timeout = process::valid_seconds(*f);
+                    command.total_timeout = command.timeout;
+                }
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(t) => {
+                    // Every value that's set has to be a valid duration
+                    let mut invalid: Option<String> = None;
+                    let mut seconds = |key: &str| {
+                        let value = t.get(key)?;
+                        let seconds = duration_value(value);
+                        if seconds.is_none() && invalid.is_none() {
+                            invalid = Some(format!("{} = {}", key, value));
+                        }
+                        seconds
+                    };
+                    let attempt = seconds("attempt");
+                    let total = seconds("total");
+                    let grace = seconds("grace");
+                    command.timeout = attempt;
+                    command.total_timeout = total;
+                    command.kill_grace = grace.unwrap_or(command.kill_grace);
+                    if invalid.is_none() && attempt.is_none() && total.is_none() {
+                        invalid = Some("attempt or total has to be set".to_string());
+                    }
+                    if let Some(invalid) = invalid {
+                        command.error = Some(format!("Invalid timeout: {}", invalid));
+                    }
+                }
+            }
+            if !timeout.is_table() && command.timeout.is_none() {
+                command.error = Some(format!("Invalid timeout: {}", timeout));
+            }
+        }
+        if v.get("name").is_some() {
+            let name = v.get("name").unwrap();
+            match name {
+                toml::Value::String(s) => {
+                    command.name = s.clone();
                 }
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
                 toml::Value::Boolean(_) => {}
                 toml::Value::Datetime(_) => {}
                 toml::Value::Array(_) => {}
                 toml::Value::Table(_) => {}
             }
         }
-        if v.get("prefix").is_some() {
-            // Will only be added if I figure out how I can pass environment variables back and forth between commands
-            todo!()
+        if v.get("export_env").is_some() {
+            let export_env = v.get("export_env").unwrap();
+            match export_env {
+                toml::Value::String(_) => {}
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(b) => {
+                    command.export_env = *b;
+                }
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(_) => {}
+                toml::Value::Table(_) => {}
+            }
         }
-        if v.get("suffix").is_some() {
-            // We'll see if I add this
-            todo!()
+        for key in ["prefix", "suffix"] {
+            let hooks: Vec<&toml::Value> = match v.get(key) {
+                Some(toml::Value::Array(a)) => a.iter().collect(),
+                Some(hook) => vec![hook],
+                None => vec![],
+            };
+            for hook in hooks {
+                let mut cmd = Command::for_platforms(hook, platforms);
+                if hook.is_str() {
+                    cmd.env = command.env.clone();
+                    cmd.working_dir = command.working_dir.clone();
+                }
+                if key == "prefix" {
+                    command.prefix.push(cmd);
+                } else {
+                    command.suffix.push(cmd);
+                }
+            }
         }
         if v.get("shell").is_some() {
             let shell = v.get("shell").unwrap();
@@ -498,30 +2399,64 @@ impl From<&toml::Value> for Command {
             }
         }
 
-        if v.get("cmd").is_some() {
-            match v.get("cmd").unwrap() {
+        // Picks the variant for this platform when `cmd` or `exec` is a table of variants
+        let select_variant = |key: &str| {
+            let mut value = v.get(key).cloned();
+            if let Some(toml::Value::Table(t)) = v.get(key) {
+                if is_variant_table(t) {
+                    value = platforms.iter().find_map(|x| t.get(x)).cloned();
+                }
+            }
+            value
+        };
+        let cmd_value = select_variant("cmd");
+        let exec_value = select_variant("exec");
+        if cmd_value.is_none()
+            && exec_value.is_none()
+            && (has_variants || v.get("cmd").is_some() || v.get("exec").is_some())
+        {
+            command.error = Some(format!(
+                "The command has no variant for {}",
+                platforms.join(" or ")
+            ));
+        }
+        if cmd_value.is_some() && exec_value.is_some() {
+            command.error = Some("A command can't have both cmd and exec".to_string());
+        }
+
+        if let Some(exec_value) = &exec_value {
+            match exec_value {
                 toml::Value::String(s) => {
-                    if s.contains('\n') {
-                        let mut cmd = s.clone();
-                        for (arg, val) in &command.args {
-                            cmd = cmd.replace(format!("${}", arg).as_str(), val.as_str())
-                        }
-                        let mut file =
-                            tempfile::NamedTempFile::new().expect("failed to create temp file");
-                        let _ = file.write(cmd.as_bytes()).expect("failed to write data");
-                        let _ = file.flush().expect("failed to write data");
-                        let path = file.path();
-                        command.command = vec![path.to_str().unwrap().to_string()];
-                        command.file_handles.push(file);
-                    } else {
-                        let parts: Vec<String> = s.split(' ').map(|x| x.to_string()).collect();
-                        command.command = parts.clone();
-                        for c in command.command.iter_mut() {
-                            for (arg, val) in &command.args {
-                                *c = c.replace(format!("${}", arg).as_str(), val.as_str())
-                            }
-                        }
-                    }
+                    command.exec = true;
+                    command.command = split_words(s);
+                }
+                toml::Value::Integer(_) => {}
+                toml::Value::Float(_) => {}
+                toml::Value::Boolean(_) => {}
+                toml::Value::Datetime(_) => {}
+                toml::Value::Array(a) => {
+                    command.exec = true;
+                    command.command = a
+                        .iter()
+                        .filter_map(|x| x.as_str())
+                        .map(|x| x.to_string())
+                        .collect();
+                }
+                toml::Value::Table(_) => {}
+            }
+            command.command_line = command
+                .command
+                .iter()
+                .map(|x| quote_arg(x))
+                .collect::<Vec<String>>()
+                .join(" ");
+        }
+
+        if let Some(cmd_value) = &cmd_value {
+            match cmd_value {
+                toml::Value::String(s) => {
+                    command.command_line = s.clone();
+                    command.command = split_words(s);
                 }
                 toml::Value::Integer(_) => {}
                 toml::Value::Float(_) => {}
@@ -529,23 +2464,22 @@ impl From<&toml::Value> for Command {
                 toml::Value::Datetime(_) => {}
                 toml::Value::Array(a) => {
                     for n in a {
-                        let mut cmd = Command::from(n);
+                        let mut cmd = Command::for_platforms(n, platforms);
                         if n.is_str() {
                             cmd.args = command.args.clone();
+                            cmd.raw_args = command.raw_args.clone();
                             cmd.env = command.env.clone();
+                            cmd.quoted_env = command.quoted_env.clone();
                             cmd.working_dir = command.working_dir.clone();
                         }
-                        for c in cmd.command.iter_mut() {
-                            if cmd.args.get(&c[1..c.len()]).is_some() {
-                                *c = cmd.args.get(&c[1..c.len()]).unwrap().clone();
-                            }
-                        }
                         command.children.push(cmd);
                     }
                 }
                 toml::Value::Table(t) => {
                     if t.get("cmd").is_some() {
-                        command.children.push(Command::from(t.get("cmd").unwrap()));
+                        command
+                            .children
+                            .push(Command::for_platforms(t.get("cmd").unwrap(), platforms));
                     }
                 }
             }
@@ -560,3 +2494,310 @@ impl From<&serde_json::Value> for Command {
         Command::from(&convert_json_to_toml(v))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml(s: &str) -> toml::Value {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn platforms_are_most_specific_first() {
+        assert_eq!(platforms_for(Some("linux".to_string())), ["linux", "unix"]);
+        assert_eq!(platforms_for(Some("macos".to_string())), ["macos", "unix"]);
+        assert_eq!(platforms_for(Some("unix".to_string())), ["unix"]);
+        assert_eq!(platforms_for(Some("windows".to_string())), ["windows"]);
+        assert_eq!(platforms_for(None)[0], std::env::consts::OS);
+    }
+
+    #[test]
+    fn only_tables_of_platform_tables_are_overrides() {
+        let overrides = toml("[linux]\ncmd = \"a\"\n[windows]\ncmd = \"b\"");
+        let section = toml("linux = \"a\"\nwindows = \"b\"");
+        let mixed = toml("linux = \"a\"\nbuild = \"b\"");
+        assert!(is_platform_override_table(overrides.as_table().unwrap()));
+        assert!(!is_platform_override_table(section.as_table().unwrap()));
+        assert!(is_variant_table(section.as_table().unwrap()));
+        assert!(!is_variant_table(mixed.as_table().unwrap()));
+    }
+
+    #[test]
+    fn named_arguments_only_go_to_the_children_that_take_them() {
+        let mut section = Command::builder().build();
+        section.children.push(Command::from("echo building"));
+        section.children.push(Command::from(&toml(
+            r#"
+            cmd = "echo pushing to $env"
+            args = ["env=dev"]
+            "#,
+        )));
+        let declared = section.declared_args();
+        let args: Vec<String> = ["env=prod", "x=1", "--", "env=test"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let build_args = args_for_child(&args, &declared, &section.children[0]);
+        assert_eq!(build_args, ["x=1", "--", "env=test"]);
+        let push_args = args_for_child(&args, &declared, &section.children[1]);
+        assert_eq!(push_args, args);
+    }
+
+    #[test]
+    fn invalid_utf8_does_not_end_the_output() {
+        let output: &[u8] = b"a\xffb\r\nsecond\n\nlast";
+        let mut lines: Vec<String> = vec![];
+        for_each_line(output, |line| lines.push(line));
+        assert_eq!(lines, ["a\u{fffd}b", "second", "", "last"]);
+    }
+
+    #[test]
+    fn env_values_can_extend_the_environment() {
+        let command = Command::from(&toml(
+            r#"
+            cmd = "true"
+            env = { PATH = "/opt/x:${env.PATH}", A = "a", B = "${env.A}/b:${env.PATH}" }
+            "#,
+        ));
+        let path = std::env::var("PATH").unwrap();
+        let env = command.resolve(&[]).env;
+        assert_eq!(env["PATH"], format!("/opt/x:{}", path));
+        assert_eq!(env["B"], format!("a/b:/opt/x:{}", path));
+    }
+
+    // A variable read from a dotenv file, along with whether it's expanded or secret
+    type Variable<'a> = (&'a str, &'a str, bool);
+
+    fn to_owned(variables: &[Variable]) -> Vec<(String, String, bool)> {
+        variables
+            .iter()
+            .map(|(k, v, x)| (k.to_string(), v.to_string(), *x))
+            .collect()
+    }
+
+    #[test]
+    fn dotenv_files_are_parsed_like_dotenv() {
+        let cases: [(&str, &[Variable]); 13] = [
+            ("A=1", &[("A", "1", true)]),
+            ("  # comment\n\nB = two words ", &[("B", "two words", true)]),
+            ("export C=3", &[("C", "3", true)]),
+            ("D=value # comment", &[("D", "value", true)]),
+            ("E=a#b", &[("E", "a#b", true)]),
+            ("F='${X} \\n' # comment", &[("F", "${X} \\n", false)]),
+            (r#"G="say \"hi\"\n""#, &[("G", "say \"hi\"\n", true)]),
+            (
+                "H=\"line1\nline2\"\nI=2",
+                &[("H", "line1\nline2", true), ("I", "2", true)],
+            ),
+            ("J='unterminated", &[("J", "unterminated", false)]),
+            ("K", &[]),
+            ("L=", &[("L", "", true)]),
+            ("M=a=b", &[("M", "a=b", true)]),
+            ("O=1\nO=2", &[("O", "1", true), ("O", "2", true)]),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(parse_dotenv(content), to_owned(expected), "{}", content);
+        }
+    }
+
+    #[test]
+    fn closing_quotes_skip_escaped_double_quotes() {
+        let cases = [
+            ("abc", '"', None),
+            (r#"ab"c"#, '"', Some(2)),
+            (r#"a\"b"c"#, '"', Some(4)),
+            (r#"a\\"b"#, '"', Some(3)),
+            (r"a\'b'", '\'', Some(2)),
+            ("", '\'', None),
+        ];
+        for (value, quote, expected) in cases {
+            assert_eq!(find_closing_quote(value, quote), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn later_dotenv_files_override_earlier_ones_but_not_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| std::fs::write(dir.path().join(name), content);
+        write(".env", "HOST=localhost\nPORT=5432\nA=from_file").unwrap();
+        write(
+            ".env.local",
+            "HOST=db\nURL=postgres://${HOST}:${PORT}/db\nB='${HOST}'",
+        )
+        .unwrap();
+        write(".env.secret", "TOKEN=abc").unwrap();
+        let mut command = Command::from(&toml(
+            r#"
+            cmd = "true"
+            env = { A = "own" }
+            env_file = [".env", ".env.local", ".env.missing", ".env.secret"]
+            "#,
+        ));
+        command.set_base_dir(dir.path());
+        let variables = command.read_env_files().unwrap();
+        let expected = [
+            ("PORT", "5432", false),
+            ("HOST", "db", false),
+            ("URL", "postgres://db:5432/db", false),
+            ("B", "${HOST}", false),
+            ("TOKEN", "abc", true),
+        ];
+        assert_eq!(variables, to_owned(&expected));
+    }
+
+    #[test]
+    fn split_words_follows_posix_shell_rules() {
+        assert_eq!(split_words("a  b\tc"), ["a", "b", "c"]);
+        assert_eq!(
+            split_words(r#"git commit -m "two words""#),
+            ["git", "commit", "-m", "two words"]
+        );
+        assert_eq!(split_words(r#"'a "b" \c'"#), [r#"a "b" \c"#]);
+        assert_eq!(split_words(r#""a \"b\" \$c \d""#), [r#"a "b" $c \d"#]);
+        assert_eq!(split_words(r"a\ b c\\d"), ["a b", r"c\d"]);
+        assert_eq!(split_words("x'y'\"z\""), ["xyz"]);
+        assert_eq!(split_words("''"), [""]);
+        assert!(split_words("  ").is_empty());
+    }
+
+    #[test]
+    fn safe_arguments_are_not_quoted() {
+        for shell in ["sh", "bash", "fish", "cmd", "powershell", "python"] {
+            assert_eq!(quote_for_shell("--flag=a/b.c", shell), "--flag=a/b.c");
+        }
+    }
+
+    #[test]
+    fn posix_shells_use_single_quotes() {
+        for shell in ["sh", "bash", "zsh", "dash", "ksh", "/bin/bash -e"] {
+            assert_eq!(quote_for_shell("a b", shell), "'a b'");
+            assert_eq!(quote_for_shell("it's", shell), r"'it'\''s'");
+            assert_eq!(quote_for_shell(r"a\b $x", shell), r"'a\b $x'");
+            assert_eq!(quote_for_shell("", shell), "''");
+        }
+    }
+
+    #[test]
+    fn fish_escapes_backslashes_and_quotes() {
+        assert_eq!(quote_for_shell("a b", "fish"), "'a b'");
+        assert_eq!(quote_for_shell("it's", "fish"), r"'it\'s'");
+        assert_eq!(quote_for_shell(r"a\b", "fish"), r"'a\\b'");
+        assert_eq!(quote_for_shell(r"a\'", "fish"), r"'a\\\''");
+    }
+
+    #[test]
+    fn powershell_doubles_single_quotes() {
+        assert_eq!(quote_for_shell("it's $x", "powershell"), "'it''s $x'");
+        assert_eq!(quote_for_shell("a b", "pwsh -NoProfile"), "'a b'");
+    }
+
+    #[test]
+    fn cmd_escapes_every_special_character() {
+        assert_eq!(quote_for_shell("a b", "cmd"), r#"^"a b^""#);
+        assert_eq!(
+            quote_for_shell(r#"a" & calc & "b"#, "cmd"),
+            r#"^"a\^" ^& calc ^& \^"b^""#
+        );
+        assert_eq!(quote_for_shell("%PATH%!x!", "cmd"), r#"^"^%PATH^%^!x^!^""#);
+        assert_eq!(
+            quote_for_shell("a|b<c>d^e(f)", "cmd /C"),
+            r#"^"a^|b^<c^>d^^e^(f^)^""#
+        );
+        assert_eq!(quote_for_shell(r"C:\dir\", "cmd"), r#"^"C:\dir\\^""#);
+        assert_eq!(quote_for_shell(r#"a\"b"#, "cmd"), r#"^"a\\\^"b^""#);
+    }
+
+    #[test]
+    fn other_programs_get_the_argument_as_is() {
+        assert_eq!(quote_for_shell("a b 'c'", "python"), "a b 'c'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_gets_back_the_exact_argument() {
+        for arg in [
+            "a b",
+            "it's",
+            r"a\b",
+            "$(touch x) `y` ; rm",
+            "\"q\"",
+            "*",
+            "tab\tnew\nline",
+            "",
+        ] {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", quote_for_shell(arg, "sh")))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), arg);
+        }
+    }
+
+    #[test]
+    fn substitute_args_quotes_values_longest_name_first() {
+        let args = HashMap::from([
+            ("name".to_string(), "a b".to_string()),
+            ("name_long".to_string(), "x".to_string()),
+            ("flags".to_string(), "--a --b".to_string()),
+        ]);
+        let raw = vec!["flags".to_string()];
+        assert_eq!(
+            substitute_args("echo $name $name_long $flags", &args, &raw, Some("sh")),
+            "echo 'a b' x --a --b"
+        );
+        assert_eq!(
+            substitute_args("echo $name", &args, &raw, Some("cmd")),
+            r#"echo ^"a b^""#
+        );
+        assert_eq!(
+            substitute_args("echo $name $unknown", &args, &raw, None),
+            "echo a b $unknown"
+        );
+    }
+
+    #[test]
+    fn variants_and_overrides_follow_the_platform() {
+        let hello = toml(
+            "cmd = { unix = \"echo unix\", windows = \"echo windows\" }\n\
+             env = [\"A=1\"]\n\
+             [linux]\n\
+             env = [\"A=2\"]\n\
+             working_dir = \"linux\"",
+        );
+        let only_windows = toml("cmd = { windows = \"echo windows\" }");
+        let linux = platforms_for(Some("linux".to_string()));
+        let windows = platforms_for(Some("windows".to_string()));
+
+        let command = Command::for_platforms(&hello, &linux);
+        assert_eq!(command.command_line, "echo unix");
+        assert_eq!(command.env["A"], "2");
+        assert_eq!(command.working_dir, "linux");
+        let merged = apply_platform_overrides(&hello, &linux);
+        assert!(merged.get("linux").is_none());
+        assert_eq!(merged["working_dir"].as_str(), Some("linux"));
+        assert_eq!(
+            Command::for_platforms(&only_windows, &linux)
+                .error
+                .as_deref(),
+            Some("The command has no variant for linux or unix")
+        );
+
+        let command = Command::for_platforms(&hello, &windows);
+        assert_eq!(command.command_line, "echo windows");
+        assert_eq!(command.env["A"], "1");
+        assert_eq!(command.working_dir, "");
+        assert!(apply_platform_overrides(&hello, &windows)
+            .get("working_dir")
+            .is_none());
+        assert!(Command::for_platforms(&only_windows, &windows)
+            .error
+            .is_none());
+
+        let nested =
+            toml("cmd = [{ cmd = { linux = \"echo linux\", windows = \"echo windows\" } }]");
+        let command = Command::for_platforms(&nested, &linux);
+        assert_eq!(command.children[0].command_line, "echo linux");
+    }
+}
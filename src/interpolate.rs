@@ -0,0 +1,187 @@
+use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Leaves `${git.branch}` and `${git.sha}` as they're written from now on, so `--dry-run` doesn't
+/// run git
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::SeqCst);
+}
+
+/// Replaces `${...}` variables in a string before it's handed to the shell, so commands behave
+/// the same in `sh` and `cmd`. `$${` is left as a literal `${`.
+///
+/// - `${env.NAME}` is an environment variable, empty if it isn't set
+/// - `${project.root}` is the directory of the file the command is defined in
+/// - `${cwd}` is the directory cargo-commander was started in
+/// - `${cargo.package.name}` and `${cargo.package.version}` come from the closest `Cargo.toml`
+/// - `${git.branch}` and `${git.sha}` describe the checked out commit, except in a dry run
+/// - `${NAME}` is an environment variable, left as is if it isn't set
+/// - `${env.NAME:-fallback}` and `${NAME:-fallback}` use the fallback when the variable is unset
+///   or empty, which works for every `${name.field}` variable
+///
+/// Positional and special parameters of the shell, like `${1:-default}` or `${@}`, are left as
+/// they are.
+pub fn interpolate(s: &str, env: &HashMap<String, String>, project_root: &Path) -> String {
+    replace_variables(s, env, project_root, false, &|_, value| value)
+}
+
+/// Like `interpolate`, for a command line that's handed to a shell. `${NAME}` and
+/// `${NAME:-fallback}` are left for the shell so variables the script sets itself keep working,
+/// which makes `${env.NAME}` the form that works in every shell. Values are passed through
+/// `escape` with their name before they're inserted, e.g. to quote them for the shell.
+pub fn interpolate_for_shell(
+    s: &str,
+    env: &HashMap<String, String>,
+    project_root: &Path,
+    escape: &dyn Fn(&str, String) -> String,
+) -> String {
+    replace_variables(s, env, project_root, true, escape)
+}
+
+fn replace_variables(
+    s: &str,
+    env: &HashMap<String, String>,
+    project_root: &Path,
+    for_shell: bool,
+    escape: &dyn Fn(&str, String) -> String,
+) -> String {
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if start > 0 && rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let variable = &rest[start + 2..end];
+        let (name, fallback) = match variable.split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (variable, None),
+        };
+        // Positional and special parameters like `${1}` or `${#}` belong to the shell, and so do
+        // plain variables in a command line
+        let is_valid = !name.is_empty()
+            && !name.starts_with(|x: char| x.is_ascii_digit())
+            && name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '.');
+        let is_shell_variable = for_shell && !name.contains('.');
+        let is_dry_run_git = name.starts_with("git.") && DRY_RUN.load(Ordering::SeqCst);
+        if !is_valid || is_shell_variable || is_dry_run_git {
+            result.push_str(&rest[start..=end]);
+            rest = &rest[end + 1..];
+            continue;
+        }
+        match (resolve(name, env, project_root), fallback) {
+            (Some(value), _) if !value.is_empty() || fallback.is_none() => {
+                result.push_str(&escape(name, value))
+            }
+            (_, Some(fallback)) => result.push_str(fallback),
+            (None, None) if name.contains('.') => {}
+            _ => result.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn resolve(name: &str, env: &HashMap<String, String>, project_root: &Path) -> Option<String> {
+    match name {
+        "project.root" => Some(project_root.to_string_lossy().to_string()),
+        "cwd" => std::env::current_dir()
+            .ok()
+            .map(|x| x.to_string_lossy().to_string()),
+        "cargo.package.name" => get_cargo_package_field(project_root, "name"),
+        "cargo.package.version" => get_cargo_package_field(project_root, "version"),
+        "git.branch" => git(project_root, &["rev-parse", "--abbrev-ref", "HEAD"]),
+        "git.sha" => git(project_root, &["rev-parse", "HEAD"]),
+        _ => {
+            let name = name.strip_prefix("env.").unwrap_or(name);
+            env.get(name).cloned().or_else(|| std::env::var(name).ok())
+        }
+    }
+}
+
+/// Reads a field of `[package]` from the closest `Cargo.toml`, following `field.workspace = true`
+/// to the workspace's `[workspace.package]`
+fn get_cargo_package_field(dir: &Path, field: &str) -> Option<String> {
+    for dir in dir.ancestors() {
+        let cargo_toml = match utils::read_toml(&dir.join("Cargo.toml")) {
+            Some(cargo_toml) => cargo_toml,
+            None => continue,
+        };
+        let value = cargo_toml.get("package")?.get(field)?;
+        if let Some(value) = value.as_str() {
+            return Some(value.to_string());
+        }
+        if value.get("workspace").and_then(|x| x.as_bool()) == Some(true) {
+            let root = crate::workspace::find_workspace_root(dir)?;
+            let workspace_toml = utils::read_toml(&root.join("Cargo.toml"))?;
+            let value = workspace_toml
+                .get("workspace")?
+                .get("package")?
+                .get(field)?;
+            return value.as_str().map(|x| x.to_string());
+        }
+        return None;
+    }
+    None
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_and_defaults_follow_one_rule() {
+        let env = HashMap::from([("NAME".to_string(), "alice".to_string())]);
+        let root = Path::new(".");
+        let cases = [
+            ("${NAME}", "alice", "${NAME}"),
+            ("${NAME:-bob}", "alice", "${NAME:-bob}"),
+            ("${UNSET_NAME_X}", "${UNSET_NAME_X}", "${UNSET_NAME_X}"),
+            ("${UNSET_NAME_X:-bob}", "bob", "${UNSET_NAME_X:-bob}"),
+            ("${env.NAME}", "alice", "'alice'"),
+            ("${env.NAME:-bob}", "alice", "'alice'"),
+            ("${env.UNSET_NAME_X}", "", ""),
+            ("${env.UNSET_NAME_X:-bob}", "bob", "bob"),
+            ("${1:-world}", "${1:-world}", "${1:-world}"),
+            ("${1}", "${1}", "${1}"),
+            ("${@}", "${@}", "${@}"),
+            ("${#}", "${#}", "${#}"),
+            ("$${env.NAME}", "${env.NAME}", "${env.NAME}"),
+            ("a ${env.NAME", "a ${env.NAME", "a ${env.NAME"),
+        ];
+        for (input, resolved, for_shell) in cases {
+            assert_eq!(interpolate(input, &env, root), resolved, "{}", input);
+            let quoted = interpolate_for_shell(input, &env, root, &|_, x| format!("'{}'", x));
+            assert_eq!(quoted, for_shell, "{}", input);
+        }
+    }
+}
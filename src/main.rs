@@ -4,8 +4,11 @@
 )]
 
 mod command;
+mod interpolate;
+mod process;
 mod script;
 mod utils;
+mod workspace;
 
 use command::Command;
 use std::borrow::BorrowMut;
@@ -28,12 +31,21 @@ fn gui() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Takes an option and the value after it from the front of the arguments, exiting with a usage
+/// error when the value is missing
+fn take_value(args: &mut Vec<String>) -> String {
+    let option = args.remove(0);
+    if args.is_empty() {
+        println!("{} needs a value, see --help", option);
+        std::process::exit(2);
+    }
+    args.remove(0)
+}
+
 fn main() -> Result<(), std::io::Error> {
     let mut args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        if args[0].contains("cargo-cmd") && args[1] == "cmd".to_string() {
-            args.remove(1);
-        }
+    if args.len() > 1 && args[0].contains("cargo-cmd") && args[1] == "cmd" {
+        args.remove(1);
     }
     args.remove(0);
 
@@ -41,16 +53,37 @@ fn main() -> Result<(), std::io::Error> {
     let mut commander_args: HashMap<String, String> = HashMap::new();
 
     let mut all_found = false;
-    while args.len() > 0 {
+    while !args.is_empty() {
         if all_found {
             command_args.push(args.remove(0));
         } else {
             if args[0].starts_with("-") {
                 if args[0] == "-f" || args[0] == "--file" {
-                    args.remove(0);
-                    commander_args.insert("file".to_string(), args.remove(0));
+                    commander_args.insert("file".to_string(), take_value(&mut args));
+                } else if args[0] == "--root-markers" {
+                    let root_markers = take_value(&mut args);
+                    commander_args.insert("root_markers".to_string(), root_markers);
+                } else if args[0] == "--workspace" {
+                    commander_args.insert("workspace".to_string(), args.remove(0));
+                } else if args[0] == "--package" {
+                    let package = take_value(&mut args);
+                    let packages = match commander_args.remove("package") {
+                        Some(packages) => format!("{},{}", packages, package),
+                        None => package,
+                    };
+                    commander_args.insert("package".to_string(), packages);
+                } else if args[0] == "--import-targets" {
+                    commander_args.insert("import_targets".to_string(), args.remove(0));
+                } else if args[0] == "-l" || args[0] == "--list" {
+                    commander_args.insert("list".to_string(), args.remove(0));
                 } else if args[0] == "-p" || args[0] == "--parallel" {
                     commander_args.insert("parallel".to_string(), args.remove(0));
+                } else if args[0] == "-j" || args[0] == "--jobs" {
+                    commander_args.insert("jobs".to_string(), take_value(&mut args));
+                } else if args[0] == "--dry-run" {
+                    commander_args.insert("dry_run".to_string(), args.remove(0));
+                } else if args[0] == "--profile" {
+                    commander_args.insert("profile".to_string(), take_value(&mut args));
                 } else if args[0] == "-h" || args[0] == "--help" {
                     println!(
                         r"cargo-commander 2.0.15
@@ -67,8 +100,22 @@ ARGS:
 
 OPTIONS:
     -h, --help           Print help information
+    -l, --list           Lists all available commands
     -f, --file PATH      Custom path to command file to parse
-    -p, --parallel       Forces all commands to run in parallel"
+    -p, --parallel       Forces all commands to run in parallel
+    -j, --jobs N         How many commands may run at the same time
+                         [default: number of CPUs]
+    --root-markers LIST  Comma separated markers that stop the search for command
+                         files in parent directories, `none` searches all of them
+                         [default: .git,workspace,root]
+    --workspace          Runs the command in every member of the Cargo workspace
+                         and every package of the npm, yarn or pnpm workspace
+    --package NAME       Only runs in the given workspace member, can be repeated
+    --import-targets     Makes Makefile targets and justfile recipes available as
+                         commands, e.g. `make.build` or just `build`
+    --profile NAME       Uses the env and args of `[profiles.NAME]`, also read from
+                         the COMMANDER_PROFILE environment variable
+    --dry-run            Prints what the command would run without running it"
                     );
                     return Ok(());
                 } else {
@@ -80,24 +127,69 @@ OPTIONS:
         }
     }
 
-    if command_args.len() == 0 {
+    if let Some(jobs) = commander_args.get("jobs") {
+        match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => process::set_jobs(jobs),
+            _ => {
+                println!("--jobs has to be a positive number");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if !commander_args.contains_key("profile") {
+        if let Ok(profile) = std::env::var("COMMANDER_PROFILE") {
+            if !profile.is_empty() {
+                commander_args.insert("profile".to_string(), profile);
+            }
+        }
+    }
+    if let Some(profile) = commander_args.get("profile") {
+        if let Err(e) = utils::get_profiles(&commander_args, profile) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if commander_args.contains_key("list") {
+        utils::print_commands(
+            &utils::get_commands_map(&commander_args),
+            commander_args.contains_key("profile"),
+        );
+        return Ok(());
+    }
+
+    if command_args.is_empty() {
         return gui();
     }
 
     let command_name = command_args.remove(0);
 
+    if commander_args.contains_key("dry_run") {
+        interpolate::set_dry_run();
+    }
+
+    if commander_args.contains_key("workspace") {
+        process::handle_interrupts();
+        let result = workspace::execute(command_name, &commander_args, command_args);
+        process::stop_services();
+        if let Err(e) = &result {
+            println!("error: {}", e);
+        }
+        std::process::exit(process::result_exit_code(&result));
+    }
+
     let mut commands_map: HashMap<String, (PathBuf, Command)> =
-        if commander_args.contains_key("file") {
-            utils::get_commands_map(commander_args.get("file"))
-        } else {
-            utils::get_commands_map(None)
-        };
+        utils::get_commands_map(&commander_args);
 
     if commander_args.contains_key("parallel") {
+        // In CI there's no point in waiting for the rest once something fails
+        let is_ci = std::env::var("CI").is_ok_and(|x| !x.is_empty() && x != "false");
         for (_, (_, command)) in commands_map.iter_mut() {
             command.parallel = true;
-            if command.children.len() > 0 {
-                utils::enable_all_parallel(command.children.borrow_mut())
+            command.fail_fast |= is_ci;
+            if !command.children.is_empty() {
+                utils::enable_all_parallel(command.children.borrow_mut(), is_ci)
             }
         }
     }
@@ -105,21 +197,37 @@ OPTIONS:
     let cmd = commands_map.remove_entry(&command_name);
     match cmd {
         None => {
+            let is_script = command_name.starts_with("https://")
+                || command_name.starts_with("http://")
+                || std::path::Path::new(&command_name).is_file();
+            if is_script && commander_args.contains_key("dry_run") {
+                println!("{} would be compiled and run as a script", command_name);
+                return Ok(());
+            }
             if command_name.starts_with("https://") || command_name.starts_with("http://") {
                 return script::execute(command_name, "http", command_args);
             } else if std::path::Path::new(&command_name).is_file() {
                 return script::execute(command_name, "file", command_args);
             } else {
                 println!("Command not found!");
-                return Ok(());
+                std::process::exit(1);
+            }
+        }
+        Some((_, (_, command))) if commander_args.contains_key("dry_run") => {
+            println!("{}", command_name);
+            if let Err(e) = command.print_plan(command_args, 4) {
+                println!("error: {}", e);
+                std::process::exit(1);
             }
         }
-        Some((_, (dir, command))) => {
-            let _ = std::env::set_current_dir(dir);
-            for (k, v) in &command.args {
-                command_args.retain(|x| *x != format!("{}={}", k, v));
+        Some((_, (_, command))) => {
+            process::handle_interrupts();
+            let result = command.execute(command_args);
+            process::stop_services();
+            if let Err(e) = &result {
+                println!("error: {}", e);
             }
-            let _ = command.execute(command_args);
+            std::process::exit(process::result_exit_code(&result));
         }
     }
 
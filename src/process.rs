@@ -0,0 +1,349 @@
+use std::io::{Error, ErrorKind};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Set once Ctrl-C is pressed, every running command stops its processes when it sees it
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C so running processes, including the ones in parallel threads, are stopped
+/// instead of being left behind when Cargo Commander exits
+pub fn handle_interrupts() {
+    let _ = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst));
+}
+
+/// Stops a command, and everything it runs, when Ctrl-C is pressed or when a sibling fails in a
+/// `fail_fast` command
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    // One flag for every `fail_fast` command the command runs under
+    flags: Vec<Arc<AtomicBool>>,
+}
+
+impl Cancellation {
+    /// A cancellation that also stops the command when the flag is set
+    pub fn with_flag(&self, flag: &Arc<AtomicBool>) -> Cancellation {
+        let mut flags = self.flags.clone();
+        flags.push(flag.clone());
+        Cancellation { flags }
+    }
+    /// Whether a failing sibling can cancel the command, rather than only Ctrl-C
+    pub fn has_siblings(&self) -> bool {
+        !self.flags.is_empty()
+    }
+    pub fn is_cancelled(&self) -> bool {
+        is_interrupted() || self.flags.iter().any(|x| x.load(Ordering::SeqCst))
+    }
+    /// The error a cancelled command fails with
+    pub fn error(&self) -> Error {
+        if is_interrupted() {
+            interrupted_error()
+        } else {
+            cancelled_error()
+        }
+    }
+}
+
+/// A command running in the background until everything else is done
+struct Service {
+    child: Child,
+    // The script the service runs, which has to exist as long as it does
+    _script: Option<tempfile::NamedTempFile>,
+    grace: f64,
+}
+
+// Every service that has been started, stopped when the invocation ends
+static SERVICES: Mutex<Vec<Service>> = Mutex::new(vec![]);
+
+/// Keeps track of a service so it's stopped by `stop_services`
+pub fn add_service(child: Child, script: Option<tempfile::NamedTempFile>, grace: f64) {
+    SERVICES.lock().unwrap().push(Service {
+        child,
+        _script: script,
+        grace,
+    });
+}
+
+/// Stops every service, the last one started first since it may depend on the ones before it
+pub fn stop_services() {
+    let services: Vec<Service> = std::mem::take(&mut *SERVICES.lock().unwrap());
+    for mut service in services.into_iter().rev() {
+        let _ = stop(&mut service.child, false, service.grace);
+    }
+}
+
+// How many processes may run at the same time across the whole invocation
+static JOBS: OnceLock<JobPool> = OnceLock::new();
+
+/// A limited number of slots that running commands take turns with
+#[derive(Debug)]
+pub struct JobPool {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A slot in a job pool, which is given back when it's dropped
+pub struct Job<'a> {
+    pool: &'a JobPool,
+}
+
+impl JobPool {
+    pub fn new(size: usize) -> JobPool {
+        JobPool {
+            available: Mutex::new(size.max(1)),
+            released: Condvar::new(),
+        }
+    }
+    /// Waits for a free slot, giving up if the command is cancelled
+    pub fn acquire(&self, cancellation: &Cancellation) -> Result<Job<'_>, Error> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            if cancellation.is_cancelled() {
+                return Err(cancellation.error());
+            }
+            available = self
+                .released
+                .wait_timeout(available, Duration::from_millis(50))
+                .unwrap()
+                .0;
+        }
+        *available -= 1;
+        Ok(Job { pool: self })
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        *self.pool.available.lock().unwrap() += 1;
+        self.pool.released.notify_one();
+    }
+}
+
+/// Sets how many processes may run at the same time, which defaults to the number of CPUs
+pub fn set_jobs(jobs: usize) {
+    let _ = JOBS.set(JobPool::new(jobs));
+}
+
+/// Waits for a slot in the job pool shared by every command, so nested parallel sections
+/// don't run more processes at once than allowed
+pub fn acquire_job(cancellation: &Cancellation) -> Result<Job<'static>, Error> {
+    JOBS.get_or_init(|| JobPool::new(std::thread::available_parallelism().map_or(1, |x| x.get())))
+        .acquire(cancellation)
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn interrupted_error() -> Error {
+    Error::new(ErrorKind::Interrupted, "interrupted")
+}
+
+/// Whether the error is from a command that was cancelled because a sibling failed
+pub fn is_cancelled_error(e: &Error) -> bool {
+    e.kind() == ErrorKind::Interrupted && !is_interrupted()
+}
+
+pub fn cancelled_error() -> Error {
+    Error::new(ErrorKind::Interrupted, "cancelled")
+}
+
+pub fn timed_out_error(timeout: f64) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!("timed out after {}", format_duration(timeout)),
+    )
+}
+
+/// The code Cargo Commander exits with after running a command: the command's own exit code,
+/// 124 when it timed out like `timeout` reports it, 130 when it was interrupted, or 1 when it
+/// failed to run
+pub fn result_exit_code(result: &Result<i32, Error>) -> i32 {
+    match result {
+        Ok(status) => *status,
+        Err(e) if e.kind() == ErrorKind::TimedOut => 124,
+        Err(e) if e.kind() == ErrorKind::Interrupted => 130,
+        Err(_) => 1,
+    }
+}
+
+/// Sleeps for the given number of seconds, waking up early if the command is cancelled. Invalid
+/// durations, like negative ones, don't sleep at all.
+pub fn sleep(seconds: f64, cancellation: &Cancellation) {
+    let end = match valid_seconds(seconds) {
+        Some(seconds) => Instant::now() + Duration::from_secs_f64(seconds),
+        None => return,
+    };
+    while !cancellation.is_cancelled() && Instant::now() < end {
+        std::thread::sleep((end - Instant::now()).min(Duration::from_millis(50)));
+    }
+}
+
+/// Waits for a process to exit and returns its exit code. If the deadline passes or the
+/// command is cancelled first the process is asked to stop, and killed if it's still running
+/// after `grace` seconds.
+pub fn wait(
+    child: &mut Child,
+    deadline: Option<Instant>,
+    grace: f64,
+    cancellation: &Cancellation,
+) -> Result<i32, Error> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(exit_code(status));
+        }
+        let timed_out = deadline.is_some_and(|x| Instant::now() >= x);
+        if timed_out || cancellation.is_cancelled() {
+            stop(child, !timed_out && is_interrupted(), grace)?;
+            return Err(if timed_out {
+                Error::from(ErrorKind::TimedOut)
+            } else {
+                cancellation.error()
+            });
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Sends the process SIGINT when Ctrl-C was pressed, or SIGTERM otherwise, then SIGKILL after
+/// the grace period. Processes with a timeout or under a `fail_fast` command run in their own
+/// process group, which gets the signals as a whole.
+pub fn stop(child: &mut Child, interrupted: bool, grace: f64) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let signal = if interrupted {
+            libc::SIGINT
+        } else {
+            libc::SIGTERM
+        };
+        let pid = child.id() as libc::pid_t;
+        // Signalling the group fails if the process doesn't have one of its own
+        unsafe {
+            if libc::kill(-pid, signal) != 0 {
+                libc::kill(pid, signal);
+            }
+        }
+        let end = Instant::now() + Duration::from_secs_f64(grace);
+        while Instant::now() < end {
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (interrupted, grace);
+    let _ = child.kill();
+    child.wait()?;
+    Ok(())
+}
+
+/// The exit code of a process, or 128 plus the signal that killed it like shells report it
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Parses a duration like `90`, `1.5`, `500ms`, `30s`, `5m` or `1h` into seconds
+pub fn parse_duration(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|x: char| x.is_ascii_alphabetic()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let number: f64 = number.trim().parse().ok()?;
+    let multiplier = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    valid_seconds(number * multiplier)
+}
+
+/// The number of seconds if it can be used as a duration, which rules out negative, infinite and
+/// NaN values, and ones so large that a deadline can't be computed from them
+pub fn valid_seconds(seconds: f64) -> Option<f64> {
+    let duration = Duration::try_from_secs_f64(seconds).ok()?;
+    Instant::now().checked_add(duration)?;
+    Some(seconds)
+}
+
+/// Formats seconds the way they're usually written in command files, e.g. `5m` or `1.5s`
+pub fn format_duration(seconds: f64) -> String {
+    if seconds >= 3600.0 && seconds % 3600.0 == 0.0 {
+        format!("{}h", seconds / 3600.0)
+    } else if seconds >= 60.0 && seconds % 60.0 == 0.0 {
+        format!("{}m", seconds / 60.0)
+    } else if seconds < 1.0 {
+        format!("{}ms", (seconds * 1000.0).round())
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed_in_seconds() {
+        let cases = [
+            ("90", Some(90.0)),
+            ("1.5", Some(1.5)),
+            (" 30s ", Some(30.0)),
+            ("500ms", Some(0.5)),
+            ("5m", Some(300.0)),
+            ("1h", Some(3600.0)),
+            ("2 m", Some(120.0)),
+            ("0", Some(0.0)),
+            ("", None),
+            ("5d", None),
+            ("s", None),
+            ("-1", None),
+            ("-1s", None),
+            ("NaN", None),
+            ("inf", None),
+            ("1e300h", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_duration(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn only_usable_durations_are_valid() {
+        assert_eq!(valid_seconds(2.5), Some(2.5));
+        assert_eq!(valid_seconds(-1.0), None);
+        assert_eq!(valid_seconds(f64::NAN), None);
+        assert_eq!(valid_seconds(f64::INFINITY), None);
+        assert_eq!(valid_seconds(f64::MAX), None);
+    }
+
+    #[test]
+    fn durations_are_formatted_in_the_largest_whole_unit() {
+        let cases = [
+            (3600.0, "1h"),
+            (7200.0, "2h"),
+            (120.0, "2m"),
+            (90.0, "90s"),
+            (1.5, "1.5s"),
+            (0.5, "500ms"),
+            (0.0, "0ms"),
+        ];
+        for (seconds, expected) in cases {
+            assert_eq!(format_duration(seconds), expected);
+        }
+    }
+}
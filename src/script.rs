@@ -7,22 +7,21 @@ fn compile_run_rust(path: &String, args: Vec<String>) -> Result<(), std::io::Err
         .into_string()
         .unwrap();
 
-    let spawned_child;
-    if cfg!(windows) {
-        spawned_child = std::process::Command::new("cmd")
+    let spawned_child = if cfg!(windows) {
+        std::process::Command::new("cmd")
             .arg("/C")
             .arg("rustc")
             .arg(path)
             .args(["-o", fname.as_str()])
             .spawn()
-            .expect("failed to spawn");
+            .expect("failed to spawn")
     } else {
-        spawned_child = std::process::Command::new("rustc")
+        std::process::Command::new("rustc")
             .arg(path)
             .args(["-o", fname.as_str()])
             .spawn()
-            .expect("failed to spawn");
-    }
+            .expect("failed to spawn")
+    };
 
     let output = spawned_child.wait_with_output()?;
     let exit_status = output.status.code().unwrap();
@@ -31,20 +30,19 @@ fn compile_run_rust(path: &String, args: Vec<String>) -> Result<(), std::io::Err
         panic!("failed to compile script");
     }
 
-    let spawned_child;
-    if cfg!(windows) {
-        spawned_child = std::process::Command::new("cmd")
+    let spawned_child = if cfg!(windows) {
+        std::process::Command::new("cmd")
             .arg("/C")
             .arg(fname.as_str())
             .args(args)
             .spawn()
-            .expect("failed to spawn");
+            .expect("failed to spawn")
     } else {
-        spawned_child = std::process::Command::new(fname.as_str())
+        std::process::Command::new(fname.as_str())
             .args(args)
             .spawn()
-            .expect("failed to spawn");
-    }
+            .expect("failed to spawn")
+    };
 
     let _output = spawned_child.wait_with_output()?;
     Ok(())
@@ -58,7 +56,7 @@ fn execute_http(command_name: String, args: Vec<String>) -> Result<(), std::io::
             let fname_str = response
                 .url()
                 .path_segments()
-                .and_then(|segments| segments.last())
+                .and_then(|mut segments| segments.next_back())
                 .and_then(|name| if name.is_empty() { None } else { Some(name) })
                 .unwrap_or("tmp.bin");
             let fname = tmp_dir.path().join(fname_str);
@@ -68,7 +66,7 @@ fn execute_http(command_name: String, args: Vec<String>) -> Result<(), std::io::
 
             let path = &fname.into_os_string().into_string().unwrap();
 
-            return compile_run_rust(&path, args);
+            return compile_run_rust(path, args);
         }
         Err(e) => {
             println!("error: {}", e);
@@ -78,7 +76,7 @@ fn execute_http(command_name: String, args: Vec<String>) -> Result<(), std::io::
 }
 
 fn execute_file(command_name: String, args: Vec<String>) -> Result<(), std::io::Error> {
-    return compile_run_rust(&command_name, args);
+    compile_run_rust(&command_name, args)
 }
 
 pub fn execute(
@@ -87,10 +85,10 @@ pub fn execute(
     args: Vec<String>,
 ) -> Result<(), std::io::Error> {
     if variant == "http" {
-        return execute_http(command_name, args);
+        execute_http(command_name, args)
     } else if variant == "file" {
-        return execute_file(command_name, args);
+        execute_file(command_name, args)
     } else {
-        return Ok(());
+        Ok(())
     }
 }
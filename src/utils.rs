@@ -1,22 +1,30 @@
 use crate::Command;
 use std::borrow::BorrowMut;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn handle_toml_value(value: &toml::Value) -> Vec<(String, Command)> {
     let mut map: Vec<(String, Command)> = vec![];
     for (key, value) in value.as_table().unwrap() {
         if value.is_table() {
-            if value.as_table().unwrap().contains_key("cmd") {
+            let table = value.as_table().unwrap();
+            if table.contains_key("cmd")
+                || table.contains_key("exec")
+                || crate::command::is_platform_override_table(table)
+            {
                 map.push((key.clone(), Command::from(value)))
             } else {
-                for (k, command) in handle_toml_value(&value) {
+                for (k, command) in handle_toml_value(value) {
                     map.push((format!("{}.{}", key, k), command));
                 }
                 let mut parent_cmd = Command::builder().build();
                 parent_cmd.is_section = true;
-                for (_, command) in handle_toml_value(&value) {
+                for (k, mut command) in handle_toml_value(value) {
                     if !command.is_section {
+                        // Commands in a section can refer to each other's outputs by their key
+                        if command.name.is_empty() {
+                            command.name = k;
+                        }
                         parent_cmd.children.push(command);
                     }
                 }
@@ -30,191 +38,567 @@ fn handle_toml_value(value: &toml::Value) -> Vec<(String, Command)> {
     map
 }
 
-fn handle_toml(file_path: PathBuf) -> Vec<(String, Command)> {
+/// Returns the `[workspace.metadata.commands]` of the workspace a `Cargo.toml` belongs to
+fn get_workspace_commands(file_path: &Path) -> toml::value::Table {
+    let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+    if let Some(root) = crate::workspace::find_workspace_root(dir) {
+        if let Some(cargo_toml) = read_toml(&root.join("Cargo.toml")) {
+            let commands = cargo_toml
+                .get("workspace")
+                .and_then(|x| x.get("metadata"))
+                .and_then(|x| x.get("commands"))
+                .and_then(|x| x.as_table());
+            if let Some(commands) = commands {
+                return commands.clone();
+            }
+        }
+    }
+    toml::value::Table::new()
+}
+
+/// Adds a member's command on top of the inherited workspace commands. A command with
+/// `workspace = true` uses the workspace definition, with its other fields overriding it.
+fn inherit_command(commands: &mut toml::value::Table, key: &str, value: &toml::Value) {
+    let inherits = value
+        .get("workspace")
+        .and_then(|x| x.as_bool())
+        .unwrap_or(false);
+    if !inherits {
+        commands.insert(key.to_string(), value.clone());
+        return;
+    }
+    let mut table = match commands.get(key) {
+        Some(toml::Value::Table(t)) => t.clone(),
+        Some(inherited) => {
+            let mut t = toml::value::Table::new();
+            t.insert("cmd".to_string(), inherited.clone());
+            t
+        }
+        None => {
+            println!(
+                "Command `{}` is inherited from the workspace but the workspace doesn't define it",
+                key
+            );
+            return;
+        }
+    };
+    for (k, v) in value.as_table().unwrap() {
+        // `cmd` and `exec` replace each other
+        match k.as_str() {
+            "cmd" => {
+                table.remove("exec");
+            }
+            "exec" => {
+                table.remove("cmd");
+            }
+            _ => {}
+        }
+        if k != "workspace" {
+            table.insert(k.clone(), v.clone());
+        }
+    }
+    commands.insert(key.to_string(), toml::Value::Table(table));
+}
+
+/// The command files being included, and the include cycles found among them
+#[derive(Default)]
+struct Includes {
+    stack: Vec<PathBuf>,
+    cycles: Vec<Vec<PathBuf>>,
+}
+
+/// Reads the files listed under `include`, either as an array of paths or globs that are
+/// merged into the including file, or as `[include.name] path = "..."` tables whose commands
+/// are exposed as `name.*`. The array can also hold `{ name = "...", path = "..." }` tables, so
+/// both kinds can be used together. Paths are relative to the including file, and the included
+/// commands run relative to the file they're defined in.
+fn handle_includes(
+    file_path: &Path,
+    include: &toml::Value,
+    includes: &mut Includes,
+) -> Vec<(String, Command)> {
     let mut map: Vec<(String, Command)> = vec![];
-    let commands_toml_file: String =
-        std::fs::read_to_string(&file_path).expect("Something went wrong reading the file");
-    let commands_toml: toml::Value = toml::from_str(&commands_toml_file).expect("");
-    let is_cargo_toml = file_path.file_name().unwrap() == "Cargo.toml";
-    for (key, value) in commands_toml.as_table().unwrap() {
-        if is_cargo_toml {
-            if commands_toml.get("commands").is_some() {
-                for (key, value) in commands_toml.get("commands").unwrap().as_table().unwrap() {
-                    if value.is_table() {
-                        if value.as_table().unwrap().contains_key("cmd") {
-                            map.push((key.clone(), Command::from(value)));
-                        } else {
-                            for (k, command) in handle_toml_value(&value) {
-                                map.push((format!("{}.{}", key, k), command));
+    let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut patterns: Vec<(Option<String>, String)> = vec![];
+    match include {
+        toml::Value::String(s) => patterns.push((None, s.clone())),
+        toml::Value::Array(a) => {
+            for n in a {
+                match n {
+                    toml::Value::String(s) => patterns.push((None, s.clone())),
+                    toml::Value::Table(t) => {
+                        let namespace = t.get("name").and_then(|x| x.as_str());
+                        match t.get("path").and_then(|x| x.as_str()) {
+                            Some(path) => {
+                                patterns.push((namespace.map(|x| x.to_string()), path.to_string()))
                             }
-                            let mut parent_cmd = Command::builder().build();
-                            parent_cmd.is_section = true;
-                            for (_, command) in handle_toml_value(&value) {
-                                if !command.is_section {
-                                    parent_cmd.children.push(command)
-                                }
+                            None => {
+                                println!("Include `{}` is missing a path", namespace.unwrap_or(""))
                             }
-                            map.push((key.clone(), parent_cmd));
                         }
-                    } else {
-                        map.push((key.clone(), Command::from(value)));
                     }
+                    _ => {}
                 }
             }
-            if commands_toml.get("package").is_some() {
-                let pkg = commands_toml.get("package").unwrap();
-                if pkg.get("metadata").is_some() {
-                    let meta = pkg.get("metadata").unwrap();
-                    if meta.get("commands").is_some() {
-                        let cmds = meta.get("commands").unwrap();
-                        for (key, value) in cmds.as_table().unwrap() {
-                            if value.is_table() {
-                                if value.as_table().unwrap().contains_key("cmd") {
-                                    map.push((key.clone(), Command::from(value)));
-                                } else {
-                                    for (k, command) in handle_toml_value(&value) {
-                                        map.push((format!("{}.{}", key, k), command));
-                                    }
-                                    let mut parent_cmd = Command::builder().build();
-                                    parent_cmd.is_section = true;
-                                    for (_, command) in handle_toml_value(&value) {
-                                        if !command.is_section {
-                                            parent_cmd.children.push(command)
-                                        }
-                                    }
-                                    map.push((key.clone(), parent_cmd));
-                                }
-                            } else {
-                                map.push((key.clone(), Command::from(value)));
-                            }
-                        }
-                    }
+        }
+        toml::Value::Table(t) => {
+            for (namespace, value) in t {
+                let path = match value {
+                    toml::Value::String(s) => Some(s.as_str()),
+                    toml::Value::Table(t) => t.get("path").and_then(|x| x.as_str()),
+                    _ => None,
+                };
+                match path {
+                    Some(path) => patterns.push((Some(namespace.clone()), path.to_string())),
+                    None => println!("Include `{}` is missing a path", namespace),
                 }
             }
-        } else {
-            if value.is_table() {
-                if value.as_table().unwrap().contains_key("cmd") {
-                    map.push((key.clone(), Command::from(value)));
-                } else {
-                    for (k, command) in handle_toml_value(&value) {
-                        map.push((format!("{}.{}", key, k), command));
-                    }
-                    let mut parent_cmd = Command::builder().build();
-                    parent_cmd.is_section = true;
-                    for (_, command) in handle_toml_value(&value) {
-                        if !command.is_section {
-                            parent_cmd.children.push(command)
-                        }
+        }
+        _ => {}
+    }
+    for (namespace, pattern) in patterns {
+        let mut files: Vec<PathBuf> = match glob::glob(&dir.join(&pattern).to_string_lossy()) {
+            Ok(paths) => paths.flatten().filter(|x| x.is_file()).collect(),
+            Err(_) => vec![],
+        };
+        if files.is_empty() {
+            println!("No files found to include for `{}`", pattern);
+        }
+        files.sort_unstable();
+        for included_file in files {
+            let included_dir = included_file.parent().unwrap().to_path_buf();
+            let mut commands = handle_toml_file(included_file, includes);
+            for (_, command) in commands.iter_mut() {
+                command.set_base_dir(&included_dir);
+            }
+            match &namespace {
+                Some(namespace) => {
+                    for (name, command) in commands {
+                        map.push((format!("{}.{}", namespace, name), command));
                     }
-                    map.push((key.clone(), parent_cmd));
                 }
-            } else {
-                map.push((key.clone(), Command::from(value)));
+                None => map.append(&mut commands),
             }
         }
     }
     map
 }
 
-fn handle_json(file_path: PathBuf) -> Vec<(String, Command)> {
-    let mut map: Vec<(String, Command)> = vec![];
-    let json: serde_json::Value =
-        serde_json::from_reader(std::fs::File::open(&file_path).unwrap()).unwrap();
-    let scripts = json.get("scripts");
-    if scripts.is_some() {
-        for (k, v) in scripts.unwrap().as_object().unwrap() {
-            let mut x = Command::from(v);
-            let existing_paths = std::env::var_os("PATH");
-            if existing_paths.is_some() {
-                let mut paths = Vec::new();
-                for path in std::env::split_paths(&existing_paths.unwrap()) {
-                    paths.push(path)
+pub fn handle_toml(file_path: PathBuf) -> Vec<(String, Command)> {
+    handle_toml_file(file_path, &mut Includes::default())
+}
+
+fn handle_toml_file(file_path: PathBuf, includes: &mut Includes) -> Vec<(String, Command)> {
+    let canonical_path = file_path
+        .canonicalize()
+        .unwrap_or_else(|_| file_path.clone());
+    // The commands of a file that includes itself are already there, the cycle is only
+    // reported when one of them runs
+    if let Some(start) = includes.stack.iter().position(|x| *x == canonical_path) {
+        let mut cycle = includes.stack[start..].to_vec();
+        cycle.push(canonical_path);
+        includes.cycles.push(cycle);
+        return vec![];
+    }
+    let commands_toml = match read_commands_file(&file_path) {
+        Ok(commands_toml) => commands_toml,
+        Err(e) => {
+            println!("Failed to read {}: {}", file_path.to_string_lossy(), e);
+            return vec![];
+        }
+    };
+    let is_cargo_toml = file_path.file_name().unwrap() == "Cargo.toml";
+    let mut commands = toml::value::Table::new();
+    if is_cargo_toml {
+        commands = get_workspace_commands(&file_path);
+        let package_commands = commands_toml
+            .get("package")
+            .and_then(|x| x.get("metadata"))
+            .and_then(|x| x.get("commands"));
+        for section in [commands_toml.get("commands"), package_commands]
+            .iter()
+            .flatten()
+        {
+            if let Some(table) = section.as_table() {
+                for (key, value) in table {
+                    inherit_command(&mut commands, key, value);
                 }
-                let mut node_modules_path = file_path.clone();
-                node_modules_path.pop();
-                node_modules_path.push("node_modules");
-                node_modules_path.push(".bin");
-                paths.push(node_modules_path);
-                x.env.insert(
-                    "PATH".to_string(),
-                    std::env::join_paths(paths)
-                        .unwrap()
-                        .to_string_lossy()
-                        .to_string(),
-                );
+            }
+        }
+    } else if let Some(table) = commands_toml.as_table() {
+        for (key, value) in table {
+            // `root = true` marks the project root, not a command
+            if key == "root" && value.is_bool() {
+                continue;
+            }
+            if key == "include" || key == "profiles" {
+                continue;
+            }
+            commands.insert(key.clone(), value.clone());
+        }
+    }
+    let mut map: Vec<(String, Command)> = vec![];
+    if let Some(include) = commands_toml.get("include") {
+        if !is_cargo_toml {
+            includes.stack.push(canonical_path.clone());
+            map = handle_includes(&file_path, include, includes);
+            includes.stack.pop();
+        }
+    }
+    // Commands in the including file take precedence over included ones
+    let mut file_commands = handle_toml_value(&toml::Value::Table(commands));
+    for cycle in includes
+        .cycles
+        .iter()
+        .filter(|x| x.contains(&canonical_path))
+    {
+        let cycle: Vec<String> = cycle
+            .iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect();
+        for (_, command) in file_commands.iter_mut() {
+            command.warning = Some(format!("Include cycle detected: {}", cycle.join(" -> ")));
+        }
+    }
+    map.append(&mut file_commands);
+    map
+}
+
+/// Returns PATH with the `node_modules/.bin` folder of the directory and of every parent
+/// directory in front of it, closest first like npm does
+fn get_node_path(dir: &Path) -> Option<String> {
+    let mut paths: Vec<PathBuf> = dir
+        .ancestors()
+        .map(|x| x.join("node_modules").join(".bin"))
+        .filter(|x| x.is_dir())
+        .collect();
+    let existing_paths = std::env::var_os("PATH")?;
+    paths.extend(std::env::split_paths(&existing_paths));
+    std::env::join_paths(paths)
+        .ok()
+        .map(|x| x.to_string_lossy().to_string())
+}
+
+/// Creates a command for a single npm lifecycle event, with the environment variables npm sets
+fn npm_lifecycle_command(
+    package: &serde_json::Value,
+    name: &str,
+    script: &serde_json::Value,
+) -> Command {
+    let mut command = Command::from(script);
+    command.add_env("npm_lifecycle_event", name);
+    if let Some(script) = script.as_str() {
+        command.add_env("npm_lifecycle_script", script);
+    }
+    for key in ["name", "version"] {
+        if let Some(value) = package.get(key).and_then(|x| x.as_str()) {
+            command.add_env(&format!("npm_package_{}", key), value);
+        }
+    }
+    command
+}
+
+/// Creates a command for an npm script, running its `pre` and `post` scripts around it and
+/// appending any extra arguments to the script itself
+fn npm_script(
+    package: &serde_json::Value,
+    scripts: &serde_json::Map<String, serde_json::Value>,
+    name: &str,
+) -> Command {
+    let mut command = npm_lifecycle_command(package, name, &scripts[name]);
+    let pre = format!("pre{}", name);
+    if let Some(script) = scripts.get(&pre) {
+        command
+            .prefix
+            .push(npm_lifecycle_command(package, &pre, script));
+    }
+    let post = format!("post{}", name);
+    if let Some(script) = scripts.get(&post) {
+        command
+            .suffix
+            .push(npm_lifecycle_command(package, &post, script));
+    }
+    command
+}
+
+pub fn handle_json(file_path: PathBuf) -> Vec<(String, Command)> {
+    let mut map: Vec<(String, Command)> = vec![];
+    let json = match read_json(&file_path) {
+        Ok(json) => json,
+        Err(e) => {
+            println!("Failed to read {}: {}", file_path.to_string_lossy(), e);
+            return vec![];
+        }
+    };
+    let is_package_json = file_path.file_name().unwrap() == "package.json";
+    let node_path = get_node_path(file_path.parent().unwrap_or_else(|| Path::new(".")));
+    if let Some(scripts) = json.get("scripts").and_then(|x| x.as_object()) {
+        for (k, v) in scripts {
+            let mut x = if is_package_json {
+                npm_script(&json, scripts, k)
+            } else {
+                Command::from(v)
+            };
+            if let Some(node_path) = &node_path {
+                x.add_env("PATH", node_path);
             }
             map.push((k.clone(), x))
         }
     }
+    // The scripts of every workspace package are available as `package.script`
+    if is_package_json {
+        let dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+        if crate::workspace::find_npm_workspace_root(dir).as_deref() == Some(dir) {
+            for (name, package_dir) in crate::workspace::get_npm_packages(dir) {
+                for (k, mut x) in handle_json(package_dir.join("package.json")) {
+                    x.set_base_dir(&package_dir);
+                    map.push((format!("{}.{}", name, k), x));
+                }
+            }
+        }
+    }
     map
 }
 
-pub fn get_commands_map(extra_file: Option<&String>) -> HashMap<String, (PathBuf, Command)> {
+/// Files that hold nothing but commands, all parsed into the same definitions
+const COMMAND_FILES: [&str; 6] = [
+    "Commands.toml",
+    "Commands.yaml",
+    "Commands.yml",
+    "commands.yaml",
+    "commands.yml",
+    "commands.json5",
+];
+
+/// Files whose targets can be imported as commands with `--import-targets`, in the order the
+/// tool itself looks for them
+const TARGET_FILES: [(&str, [&str; 3]); 2] = [
+    ("make", ["GNUmakefile", "makefile", "Makefile"]),
+    ("just", ["justfile", "Justfile", ".justfile"]),
+];
+
+/// Markers used to find the project root when none are given with `--root-markers`
+pub const DEFAULT_ROOT_MARKERS: &str = ".git,workspace,root";
+
+/// Checks whether a directory contains any of the given root markers.
+///
+/// `workspace` matches a `Cargo.toml` with a `[workspace]` section, `root` matches a
+/// `Commands.toml` with `root = true`, anything else is the name of a file or directory.
+fn is_root_dir(dir: &Path, markers: &[&str]) -> bool {
+    for marker in markers {
+        match *marker {
+            "" | "none" => {}
+            "workspace" => {
+                if let Some(cargo_toml) = read_toml(&dir.join("Cargo.toml")) {
+                    if cargo_toml.get("workspace").is_some() {
+                        return true;
+                    }
+                }
+            }
+            "root" => {
+                for file_name in COMMAND_FILES {
+                    let file_path = dir.join(file_name);
+                    if !file_path.is_file() {
+                        continue;
+                    }
+                    if let Ok(commands_toml) = read_commands_file(&file_path) {
+                        if let Some(toml::Value::Boolean(true)) = commands_toml.get("root") {
+                            return true;
+                        }
+                    }
+                }
+            }
+            name => {
+                if dir.join(name).exists() {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Reads a toml, yaml or json5 command file, converting it to toml so every format is parsed
+/// into the same command definitions
+pub fn read_commands_file(file_path: &Path) -> Result<toml::Value, String> {
+    let content = std::fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    match file_path.extension().and_then(|x| x.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str::<serde_json::Value>(&content)
+            .map(|x| convert_json_to_toml(&x))
+            .map_err(|e| e.to_string()),
+        Some("json5") => json5::from_str::<serde_json::Value>(&content)
+            .map(|x| convert_json_to_toml(&x))
+            .map_err(|e| e.to_string()),
+        _ => toml::from_str(&content).map_err(|e| e.to_string()),
+    }
+}
+
+fn read_json(file_path: &Path) -> Result<serde_json::Value, String> {
+    let content = std::fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    serde_json::from_str(&content).map_err(|e| e.to_string())
+}
+
+pub fn read_toml(file_path: &Path) -> Option<toml::Value> {
+    if !file_path.is_file() {
+        return None;
+    }
+    let content = std::fs::read_to_string(file_path).ok()?;
+    toml::from_str(&content).ok()
+}
+
+/// Returns the directories to search for command files, from the project root down to the
+/// current directory. Without a root marker every ancestor is searched.
+fn get_search_dirs(current_dir: &Path, root_markers: &str) -> Vec<PathBuf> {
+    let markers: Vec<&str> = root_markers.split(',').map(|x| x.trim()).collect();
+    let mut dirs: Vec<PathBuf> = vec![];
+    for dir in current_dir.ancestors() {
+        dirs.push(dir.to_path_buf());
+        if is_root_dir(dir, &markers) {
+            break;
+        }
+    }
+    dirs.reverse();
+    dirs
+}
+
+/// Returns the targets of a Makefile, skipping special targets, pattern rules and variables
+fn get_make_targets(content: &str) -> Vec<String> {
+    let mut targets: Vec<String> = vec![];
+    for line in content.lines() {
+        if line.starts_with(['\t', ' ', '#']) {
+            continue;
+        }
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        if line[colon..].starts_with(":=") || line[colon..].starts_with("::=") {
+            continue;
+        }
+        let names = &line[..colon];
+        if names.contains(['=', '$', '%']) {
+            continue;
+        }
+        for name in names.split_whitespace() {
+            if !name.starts_with('.') && !targets.iter().any(|x| x == name) {
+                targets.push(name.to_string());
+            }
+        }
+    }
+    targets
+}
+
+/// Returns the public recipes of a justfile, skipping settings, aliases and variables
+fn get_just_recipes(content: &str) -> Vec<String> {
+    let mut recipes: Vec<String> = vec![];
+    let mut is_private = false;
+    for line in content.lines() {
+        if line.starts_with('[') {
+            is_private = is_private || line.contains("private");
+            continue;
+        }
+        if line.starts_with(['\t', ' ', '#']) || line.trim().is_empty() {
+            continue;
+        }
+        let skip = std::mem::replace(&mut is_private, false);
+        let line = line.trim_start_matches('@');
+        let name: String = line
+            .chars()
+            .take_while(|x| x.is_alphanumeric() || *x == '_' || *x == '-')
+            .collect();
+        let rest = &line[name.len()..];
+        if skip || name.is_empty() || name.starts_with('_') || rest.trim_start().starts_with(":=") {
+            continue;
+        }
+        let is_keyword = ["alias", "export", "import", "mod", "set"].contains(&name.as_str());
+        if is_keyword && rest.starts_with(' ') {
+            continue;
+        }
+        match rest.find(':') {
+            Some(colon) if !rest[colon..].starts_with(":=") && !recipes.contains(&name) => {
+                recipes.push(name);
+            }
+            _ => {}
+        }
+    }
+    recipes
+}
+
+pub fn get_commands_map(
+    commander_args: &HashMap<String, String>,
+) -> HashMap<String, (PathBuf, Command)> {
     let current_dir = std::env::current_dir().unwrap();
-    let mut processing_dir = PathBuf::new();
+    let root_markers = commander_args
+        .get("root_markers")
+        .map(|x| x.as_str())
+        .unwrap_or(DEFAULT_ROOT_MARKERS);
+    let import_targets = commander_args.contains_key("import_targets");
     let mut files_to_read: Vec<PathBuf> = Vec::new();
-    let skip_first = if cfg!(target_os = "windows") { 1 } else { 0 };
-    for n in current_dir.iter().skip(skip_first) {
-        processing_dir.push(n);
-        let mut try_file = PathBuf::new();
-        try_file.push(processing_dir.clone());
-        // package.json
-        try_file.push("package.json");
-        if try_file.is_file() {
-            files_to_read.push(try_file.clone());
-        }
-        try_file.pop();
-        // Cargo.toml
-        try_file.push("Cargo.toml");
-        if try_file.is_file() {
-            files_to_read.push(try_file.clone());
-        }
-        try_file.pop();
-        // commands.json
-        try_file.push("commands.json");
-        if try_file.is_file() {
-            files_to_read.push(try_file.clone());
-        }
-        try_file.pop();
-        // Commands.toml
-        try_file.push("Commands.toml");
-        if try_file.is_file() {
-            files_to_read.push(try_file.clone());
-        }
-        try_file.pop();
-    }
-    if extra_file.is_some() {
-        let f = PathBuf::from(extra_file.unwrap());
+    let mut target_files: Vec<(&str, PathBuf)> = Vec::new();
+    for processing_dir in get_search_dirs(&current_dir, root_markers) {
+        if import_targets {
+            for (tool, file_names) in TARGET_FILES {
+                let try_file = file_names
+                    .iter()
+                    .map(|x| processing_dir.join(x))
+                    .find(|x| x.is_file());
+                if let Some(try_file) = try_file {
+                    target_files.push((tool, try_file));
+                }
+            }
+        }
+        // Within a directory json files go first, so the other files can override them
+        for file_name in ["package.json", "commands.json", "Cargo.toml"]
+            .iter()
+            .chain(COMMAND_FILES.iter())
+        {
+            let try_file = processing_dir.join(file_name);
+            if try_file.is_file() {
+                files_to_read.push(try_file);
+            }
+        }
+    }
+    if let Some(extra_file) = commander_args.get("file") {
+        let f = PathBuf::from(extra_file);
         if f.is_file() {
             files_to_read.push(f);
         }
     }
     let mut map: HashMap<String, (PathBuf, Command)> = HashMap::new();
 
-    files_to_read = files_to_read
-        .iter()
-        .filter(|x| x.is_file())
-        .map(|x| x.to_owned())
-        .collect();
-    files_to_read.sort_unstable();
-    files_to_read.dedup();
-    // All files found! Quick workaround for sorting where toml is last
-    let mut sorted_files: Vec<PathBuf> = Vec::new();
-    for file_path in &files_to_read {
-        if file_path.extension().unwrap() == "json" {
-            sorted_files.push(file_path.clone());
+    // Imported targets go first so that command files can override them
+    let mut bare_targets: HashMap<String, HashMap<&str, PathBuf>> = HashMap::new();
+    for (tool, file_path) in target_files {
+        let path = file_path.parent().unwrap().to_path_buf();
+        let content = std::fs::read_to_string(&file_path).unwrap_or_default();
+        let targets = if tool == "make" {
+            get_make_targets(&content)
+        } else {
+            get_just_recipes(&content)
+        };
+        for target in targets {
+            let command = Command::from(format!("{} {}", tool, target));
+            map.insert(format!("{}.{}", tool, target), (path.clone(), command));
+            bare_targets
+                .entry(target)
+                .or_default()
+                .insert(tool, path.clone());
         }
     }
-    for file_path in &files_to_read {
-        if file_path.extension().unwrap() == "toml" {
-            sorted_files.push(file_path.clone());
+
+    // The directories are searched from the root down, so files closer to the current directory
+    // override the ones above them
+    let mut sorted_files: Vec<PathBuf> = Vec::new();
+    for file_path in files_to_read {
+        if file_path.is_file() && !sorted_files.contains(&file_path) {
+            sorted_files.push(file_path);
         }
     }
 
     for file_path in sorted_files {
         let mut path = file_path.clone();
         path.pop();
-        if file_path.extension().unwrap() == "toml" {
+        if file_path.extension().unwrap_or_default() != "json" {
             for (name, command) in handle_toml(file_path) {
                 if map.contains_key(&name) {
                     map.remove(&name);
@@ -223,7 +607,7 @@ pub fn get_commands_map(extra_file: Option<&String>) -> HashMap<String, (PathBuf
                     map.insert(name, (path.clone(), command));
                 }
             }
-        } else if file_path.extension().unwrap() == "json" {
+        } else {
             for (name, command) in handle_json(file_path) {
                 if map.contains_key(&name) {
                     map.remove(&name);
@@ -234,47 +618,208 @@ pub fn get_commands_map(extra_file: Option<&String>) -> HashMap<String, (PathBuf
             }
         }
     }
+
+    // Targets are also available without the tool prefix, unless that would be ambiguous
+    for (target, tools) in bare_targets {
+        if tools.len() == 1 && !map.contains_key(&target) {
+            let (tool, path) = tools.into_iter().next().unwrap();
+            let command = Command::from(format!("{} {}", tool, target));
+            map.insert(target, (path, command));
+        }
+    }
+    let profiles = match commander_args.get("profile") {
+        Some(name) => get_profiles(commander_args, name).unwrap_or_default(),
+        None => vec![],
+    };
+    for (path, command) in map.values_mut() {
+        command.set_base_dir(path);
+        for profile in &profiles {
+            command.apply_profile(profile);
+        }
+    }
     map
 }
 
-pub fn convert_json_to_toml(json: &serde_json::Value) -> toml::Value {
-    let value: toml::Value;
-    match json {
-        serde_json::Value::Null => {
-            value = toml::Value::from(false);
+/// Returns the `[profiles.<name>]` of every command file, the one closest to the current
+/// directory last so its values win, or an error if none of them defines the profile
+pub fn get_profiles(
+    commander_args: &HashMap<String, String>,
+    name: &str,
+) -> Result<Vec<Command>, String> {
+    let current_dir = std::env::current_dir().unwrap();
+    let root_markers = commander_args
+        .get("root_markers")
+        .map(|x| x.as_str())
+        .unwrap_or(DEFAULT_ROOT_MARKERS);
+    let mut files_to_read: Vec<PathBuf> = vec![];
+    for processing_dir in get_search_dirs(&current_dir, root_markers) {
+        for file_name in COMMAND_FILES {
+            files_to_read.push(processing_dir.join(file_name));
+        }
+    }
+    if let Some(extra_file) = commander_args.get("file") {
+        files_to_read.push(PathBuf::from(extra_file));
+    }
+    let mut profiles: Vec<Command> = vec![];
+    for file_path in files_to_read.iter().filter(|x| x.is_file()) {
+        if file_path.file_name().unwrap() == "Cargo.toml" {
+            continue;
         }
-        serde_json::Value::Bool(b) => {
-            value = toml::Value::from(b.clone());
+        if let Ok(commands_toml) = read_commands_file(file_path) {
+            if let Some(profile) = commands_toml.get("profiles").and_then(|x| x.get(name)) {
+                profiles.push(Command::from(profile));
+            }
         }
-        serde_json::Value::Number(n) => {
-            value = toml::Value::from(n.as_f64().unwrap());
+    }
+    if profiles.is_empty() {
+        return Err(format!(
+            "Profile `{}` isn't defined in any command file",
+            name
+        ));
+    }
+    Ok(profiles)
+}
+
+/// Prints the name of every command, along with what it runs. With `show_values` the
+/// arguments and environment variables the commands run with are printed as well.
+pub fn print_commands(map: &HashMap<String, (PathBuf, Command)>, show_values: bool) {
+    let mut names: Vec<&String> = map.keys().collect();
+    names.sort_unstable();
+    let width = names.iter().map(|x| x.len()).max().unwrap_or(0);
+    for name in names {
+        let (_, command) = &map[name];
+        let description = command.get_description();
+        println!("    {:<width$}  {}", name, description, width = width);
+        if !show_values {
+            continue;
         }
-        serde_json::Value::String(s) => {
-            value = toml::Value::from(s.clone());
+        for (label, values) in [("args", &command.args), ("env", &command.env)] {
+            if values.is_empty() {
+                continue;
+            }
+            let mut values: Vec<String> = values
+                .iter()
+                .map(|(k, v)| match command.secret_env.contains(k) {
+                    true => format!("{}=***", k),
+                    false => format!("{}={}", k, v),
+                })
+                .collect();
+            values.sort_unstable();
+            println!(
+                "    {:<width$}    {}: {}",
+                "",
+                label,
+                values.join(" "),
+                width = width
+            );
         }
+    }
+}
+
+pub fn convert_json_to_toml(json: &serde_json::Value) -> toml::Value {
+    match json {
+        serde_json::Value::Null => toml::Value::from(false),
+        serde_json::Value::Bool(b) => toml::Value::from(*b),
+        // Integers stay integers, so e.g. `retry.attempts` and `env` values mean the same as in toml
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => toml::Value::from(n.as_f64().unwrap()),
+        },
+        serde_json::Value::String(s) => toml::Value::from(s.clone()),
         serde_json::Value::Array(a) => {
             let mut vec: Vec<toml::Value> = vec![];
             for n in a {
                 vec.push(convert_json_to_toml(n))
             }
-            value = toml::Value::from(vec);
+            toml::Value::from(vec)
         }
         serde_json::Value::Object(o) => {
             let mut map: toml::map::Map<String, toml::Value> = Default::default();
             for (k, v) in o {
                 map.insert(k.clone(), convert_json_to_toml(v));
             }
-            value = toml::Value::from(map);
+            toml::Value::from(map)
         }
     }
-    value
 }
 
-pub fn enable_all_parallel(map: &mut Vec<Command>) {
+/// Makes every command run its children in parallel, stopping them all once one fails if
+/// `fail_fast` is set
+pub fn enable_all_parallel(map: &mut Vec<Command>, fail_fast: bool) {
     for command in map {
         command.parallel = true;
-        if command.children.len() > 0 {
-            enable_all_parallel(command.children.borrow_mut());
+        command.fail_fast |= fail_fast;
+        if !command.children.is_empty() {
+            enable_all_parallel(command.children.borrow_mut(), fail_fast);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn make_targets_skip_variables_and_special_targets() {
+        let makefile = "\
+# build: commented out
+CC := gcc
+OPT ::= -O2
+FLAGS = -Wall
+URL = http://example.com
+.PHONY: build test
+build: main.o
+\tgcc -o app main.o
+test build-all: build
+%.o: %.c
+$(OUT): build
+install:
+  indented: target
+build: CFLAGS += -g
+a=b: c
+";
+        assert_eq!(
+            get_make_targets(makefile),
+            ["build", "test", "build-all", "install"]
+        );
+    }
+
+    #[test]
+    fn just_recipes_skip_settings_aliases_and_private_recipes() {
+        let justfile = "\
+set shell := [\"bash\", \"-c\"]
+alias b := build
+export FOO := \"x\"
+version := \"1.0\"
+url := \"http://example.com\"
+import 'other.just'
+mod sub
+
+default: build
+
+build target=\"debug\":
+    cargo build
+[private]
+hidden:
+    echo hidden
+[private]
+[no-cd]
+also-hidden:
+    echo hidden
+_helper:
+    echo helper
+@quiet:
+    echo quiet
+[linux]
+linux-only:
+    echo linux
+test *args: build
+    cargo test {{args}}
+build:
+";
+        assert_eq!(
+            get_just_recipes(justfile),
+            ["default", "build", "quiet", "linux-only", "test"]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use crate::utils;
+use crate::Command;
+use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Finds the closest parent directory with a `Cargo.toml` containing a `[workspace]` section
+pub fn find_workspace_root(dir: &Path) -> Option<PathBuf> {
+    for dir in dir.ancestors() {
+        if let Some(cargo_toml) = utils::read_toml(&dir.join("Cargo.toml")) {
+            if cargo_toml.get("workspace").is_some() {
+                return Some(dir.to_path_buf());
+            }
+        }
+    }
+    None
+}
+
+/// Finds the closest parent directory with a `package.json` listing `workspaces`, or a
+/// `pnpm-workspace.yaml`
+pub fn find_npm_workspace_root(dir: &Path) -> Option<PathBuf> {
+    for dir in dir.ancestors() {
+        if !get_npm_workspace_globs(dir).is_empty() {
+            return Some(dir.to_path_buf());
+        }
+    }
+    None
+}
+
+/// Returns the package globs of an npm, yarn or pnpm workspace
+fn get_npm_workspace_globs(root: &Path) -> Vec<String> {
+    let mut workspaces: Option<serde_json::Value> = None;
+    if let Ok(file) = std::fs::File::open(root.join("package.json")) {
+        if let Ok(package) = serde_json::from_reader::<_, serde_json::Value>(file) {
+            // Yarn also allows `"workspaces": { "packages": [...] }`
+            workspaces = match package.get("workspaces") {
+                Some(serde_json::Value::Object(o)) => o.get("packages").cloned(),
+                workspaces => workspaces.cloned(),
+            };
+        }
+    }
+    if let Ok(content) = std::fs::read_to_string(root.join("pnpm-workspace.yaml")) {
+        if let Ok(pnpm) = serde_yaml::from_str::<serde_json::Value>(&content) {
+            workspaces = pnpm.get("packages").cloned();
+        }
+    }
+    match workspaces {
+        Some(serde_json::Value::Array(a)) => a
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| x.to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn expand_globs(root: &Path, patterns: &[String]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = vec![];
+    for pattern in patterns {
+        let pattern = root.join(pattern);
+        if let Ok(paths) = glob::glob(&pattern.to_string_lossy()) {
+            for path in paths.flatten() {
+                if path.is_dir() && !dirs.contains(&path) {
+                    dirs.push(path);
+                }
+            }
+        }
+    }
+    dirs
+}
+
+fn toml_strings(value: Option<&toml::Value>) -> Vec<String> {
+    match value {
+        Some(toml::Value::Array(a)) => a
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| x.to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Returns the name and directory of every package of an npm, yarn or pnpm workspace. Globs
+/// starting with `!` exclude packages, like pnpm allows.
+pub fn get_npm_packages(root: &Path) -> Vec<(String, PathBuf)> {
+    let mut packages: Vec<(String, PathBuf)> = vec![];
+    let (excludes, includes): (Vec<String>, Vec<String>) = get_npm_workspace_globs(root)
+        .into_iter()
+        .partition(|x| x.starts_with('!'));
+    let excludes: Vec<String> = excludes.iter().map(|x| x[1..].to_string()).collect();
+    let excluded = expand_globs(root, &excludes);
+    for dir in expand_globs(root, &includes) {
+        if excluded.contains(&dir) {
+            continue;
+        }
+        let file = match std::fs::File::open(dir.join("package.json")) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        let package: serde_json::Value = serde_json::from_reader(file).unwrap_or_default();
+        let name = package
+            .get("name")
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| dir.file_name().unwrap().to_string_lossy().to_string());
+        packages.push((name, dir));
+    }
+    packages
+}
+
+/// Returns the name and directory of every member of the workspace, in the order they're listed
+pub fn get_members(root: &Path) -> Vec<(String, PathBuf)> {
+    let mut members: Vec<(String, PathBuf)> = vec![];
+    let cargo_toml = match utils::read_toml(&root.join("Cargo.toml")) {
+        Some(cargo_toml) => cargo_toml,
+        None => return members,
+    };
+    let mut dirs: Vec<PathBuf> = vec![];
+    // Like cargo, the root package is a member of its own workspace
+    if cargo_toml.get("package").is_some() {
+        dirs.push(root.to_path_buf());
+    }
+    if let Some(workspace) = cargo_toml.get("workspace") {
+        let excluded: Vec<PathBuf> = expand_globs(root, &toml_strings(workspace.get("exclude")))
+            .iter()
+            .filter_map(|x| x.canonicalize().ok())
+            .collect();
+        for dir in expand_globs(root, &toml_strings(workspace.get("members"))) {
+            let is_excluded = match dir.canonicalize() {
+                Ok(dir) => excluded.contains(&dir),
+                Err(_) => true,
+            };
+            if !is_excluded && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    for dir in dirs {
+        if let Some(member_toml) = utils::read_toml(&dir.join("Cargo.toml")) {
+            let name = member_toml
+                .get("package")
+                .and_then(|x| x.get("name"))
+                .and_then(|x| x.as_str())
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| dir.file_name().unwrap().to_string_lossy().to_string());
+            members.push((name, dir));
+        }
+    }
+    members
+}
+
+/// Runs a command in every member of the Cargo workspace and every package of the npm, yarn
+/// or pnpm workspace that defines it
+pub fn execute(
+    command_name: String,
+    commander_args: &HashMap<String, String>,
+    args: Vec<String>,
+) -> Result<i32, std::io::Error> {
+    let current_dir = std::env::current_dir()?;
+    // The name of every member, along with the file its commands are defined in
+    let mut member_files: Vec<(String, PathBuf)> = vec![];
+    if let Some(root) = find_workspace_root(&current_dir) {
+        for (name, dir) in get_members(&root) {
+            member_files.push((name, dir.join("Cargo.toml")));
+        }
+    }
+    if let Some(root) = find_npm_workspace_root(&current_dir) {
+        for (name, dir) in get_npm_packages(&root) {
+            member_files.push((name, dir.join("package.json")));
+        }
+    }
+    if member_files.is_empty() {
+        println!("No Cargo or npm workspace found!");
+        return Ok(1);
+    }
+    let packages: Vec<&str> = match commander_args.get("package") {
+        Some(packages) => packages.split(',').collect(),
+        None => vec![],
+    };
+    let parallel = commander_args.contains_key("parallel");
+    let profiles = match commander_args.get("profile") {
+        Some(name) => utils::get_profiles(commander_args, name).unwrap_or_default(),
+        None => vec![],
+    };
+
+    let mut members: Vec<(String, Command)> = vec![];
+    for (name, file_path) in member_files {
+        if !packages.is_empty() && !packages.contains(&name.as_str()) {
+            continue;
+        }
+        let dir = file_path.parent().unwrap().to_path_buf();
+        let commands = if file_path.file_name().unwrap() == "Cargo.toml" {
+            utils::handle_toml(file_path)
+        } else {
+            utils::handle_json(file_path)
+        };
+        let mut commands: HashMap<String, Command> = commands.into_iter().collect();
+        if let Some(mut command) = commands.remove(&command_name) {
+            command.set_base_dir(&dir);
+            for profile in &profiles {
+                command.apply_profile(profile);
+            }
+            if parallel {
+                command.parallel = true;
+                utils::enable_all_parallel(command.children.borrow_mut(), false);
+            }
+            members.push((name, command));
+        }
+    }
+    if members.is_empty() {
+        println!("Command not found in any workspace member!");
+        return Ok(1);
+    }
+
+    if commander_args.contains_key("dry_run") {
+        for (name, command) in members {
+            println!("{} in {}", command_name, name);
+            command.print_plan(args.clone(), 4)?;
+        }
+        return Ok(0);
+    }
+
+    let mut statuses: Vec<(String, Result<i32, std::io::Error>)> = vec![];
+    if parallel {
+        let mut handles = vec![];
+        for (name, command) in members {
+            let cp = args.clone();
+            handles.push((name, std::thread::spawn(|| command.execute(cp))));
+        }
+        for (name, h) in handles {
+            let status = h
+                .join()
+                .unwrap_or_else(|_| Err(std::io::Error::other("command panicked")));
+            statuses.push((name, status));
+        }
+    } else {
+        for (name, command) in members {
+            if crate::process::is_interrupted() {
+                break;
+            }
+            println!("Running `{}` in {}", command_name, name);
+            let status = command.execute(args.clone());
+            statuses.push((name, status));
+        }
+    }
+
+    // The first member that failed decides the exit code
+    let mut exit_code = 0;
+    println!("Workspace summary for `{}`:", command_name);
+    for (name, status) in statuses {
+        if exit_code == 0 {
+            exit_code = crate::process::result_exit_code(&status);
+        }
+        match status {
+            Ok(0) => println!("    ok      {}", name),
+            Ok(code) => println!("    failed  {} (exit code {})", name, code),
+            Err(e) => println!("    failed  {} ({})", name, e),
+        }
+    }
+    Ok(exit_code)
+}